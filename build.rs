@@ -0,0 +1,16 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    // The gas cost table is embedded into the binary, see `src/gas_table.rs`.
+    let table =
+        env::var("SENTINEL_GAS_COST_TABLE").unwrap_or_else(|_| "gas_cost_table.toml".to_string());
+
+    println!("cargo:rerun-if-env-changed=SENTINEL_GAS_COST_TABLE");
+    println!("cargo:rerun-if-changed={}", table);
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    fs::copy(&table, Path::new(&out_dir).join("gas_cost_table.toml"))
+        .expect("Failed to copy the gas cost table");
+}
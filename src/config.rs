@@ -0,0 +1,67 @@
+//! Configuration of sentinel.
+//!
+//! Chain operators can adjust the build-time defaults below to match their
+//! fee schedule.
+
+use pwasm_utils::rules::AlreadyMetered;
+
+use crate::error::Error;
+use crate::gas_table::GasCostTable;
+use crate::limits::ResourceLimits;
+use crate::pricing::DeploymentPricing;
+
+/// Pricing applied to every deployment processed by `main`.
+pub const DEPLOYMENT_PRICING: DeploymentPricing = DeploymentPricing {
+    base: 0,
+    per_byte: 32,
+    per_function: 0,
+    per_instruction: 0,
+    max_code_size: None,
+};
+
+/// Stack height limit applied to contracts, if any.
+///
+/// The limit is in the units of `pwasm_utils::stack_height`, i.e. the number
+/// of values (locals and operands) on the stack.
+pub const STACK_LIMIT: Option<u32> = None;
+
+/// Caps on the resources used by contracts.
+pub const RESOURCE_LIMITS: ResourceLimits = ResourceLimits::UNLIMITED;
+
+/// Handling of contracts which are already metered.
+pub const ALREADY_METERED: AlreadyMetered = AlreadyMetered::Reject;
+
+/// Strip exports not allowed by the ECI instead of rejecting the contract.
+pub const STRIP_EXTRA_EXPORTS: bool = false;
+
+/// Settings of the sentinel pipeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Gas costs applied by the metering injection.
+    pub gas_cost_table: GasCostTable,
+    /// Pricing of the deployment itself.
+    pub deployment_pricing: DeploymentPricing,
+    /// Inject a stack height limiter with this limit after metering.
+    pub stack_limit: Option<u32>,
+    /// Contracts exceeding these limits are rejected.
+    pub resource_limits: ResourceLimits,
+    /// Handling of contracts which are already metered.
+    pub already_metered: AlreadyMetered,
+    /// Strip exports not allowed by the ECI, together with the code only
+    /// reachable through them, instead of rejecting the contract.
+    pub strip_extra_exports: bool,
+}
+
+impl Config {
+    /// The configuration selected at build time.
+    pub fn embedded() -> Result<Self, Error> {
+        Ok(Config {
+            gas_cost_table: GasCostTable::embedded()?,
+            deployment_pricing: DEPLOYMENT_PRICING,
+            stack_limit: STACK_LIMIT,
+            resource_limits: RESOURCE_LIMITS,
+            already_metered: ALREADY_METERED,
+            strip_extra_exports: STRIP_EXTRA_EXPORTS,
+        })
+    }
+}
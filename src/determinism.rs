@@ -0,0 +1,67 @@
+use parity_wasm::elements::{External, Module};
+use pwasm_utils::rules;
+
+use crate::validation::{Report, Violation};
+
+/// Report constructs which are non-deterministic or not supported by ewasm.
+///
+/// Instructions forbidden by the metering `rules` are reported here as well,
+/// so they are rejected with their location before the metering is injected.
+pub(crate) fn check(module: &Module, rules: &rules::Set, report: &mut Report) {
+    let imports = module
+        .import_section()
+        .map(|section| section.entries())
+        .unwrap_or(&[]);
+
+    // Imported mutable globals can be changed by the host at any time.
+    for entry in imports {
+        if let External::Global(ref global_type) = *entry.external() {
+            if global_type.is_mutable() {
+                report.push(Violation::MutableGlobalImport {
+                    module: entry.module().to_string(),
+                    field: entry.field().to_string(),
+                });
+            }
+        }
+    }
+
+    let tables = imports
+        .iter()
+        .filter(|entry| matches!(*entry.external(), External::Table(_)))
+        .count()
+        + module
+            .table_section()
+            .map(|section| section.entries().len())
+            .unwrap_or(0);
+    if tables > 1 {
+        report.push(Violation::MultipleTables(tables));
+    }
+
+    let memories = imports
+        .iter()
+        .filter(|entry| matches!(*entry.external(), External::Memory(_)))
+        .count()
+        + module
+            .memory_section()
+            .map(|section| section.entries().len())
+            .unwrap_or(0);
+    if memories > 1 {
+        report.push(Violation::MultipleMemories(memories));
+    }
+
+    let bodies = module
+        .code_section()
+        .map(|section| section.bodies())
+        .unwrap_or(&[]);
+    for (function, body) in bodies.iter().enumerate() {
+        for (offset, instruction) in body.code().elements().iter().enumerate() {
+            if rules.process(instruction).is_err() {
+                report.push(Violation::ForbiddenInstruction {
+                    function: function as u32,
+                    offset: offset as u32,
+                    instruction: instruction.clone(),
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+use parity_wasm::elements;
+use pwasm_utils::GasError;
+
+use crate::validation::Report;
+
+/// Reasons for sentinel to reject a contract.
+///
+/// Every variant maps to a stable numeric code (see `Error::code`), which is
+/// returned to the caller together with a message as revert data.
+#[derive(Debug)]
+pub enum Error {
+    /// The contract doesn't meet the ECI/EEI restrictions.
+    Validation(Report),
+    /// Metering injection failed, e.g. due to a forbidden instruction.
+    Metering(GasError),
+    /// The metered contract could not be serialized.
+    Serialization(elements::Error),
+    /// The embedded gas cost table is malformed.
+    InvalidGasCostTable(String),
+    /// The contract exceeds the maximum code size.
+    CodeTooLarge { size: usize, limit: usize },
+    /// The stack height limiter could not be injected.
+    StackLimiter(String),
+    /// The contract is already metered and the configuration rejects it.
+    AlreadyMetered,
+}
+
+impl Error {
+    /// Stable error code of this error.
+    ///
+    /// These values are part of the external interface and must not be reused.
+    pub fn code(&self) -> u32 {
+        match *self {
+            // Codes 1 to 5, 12 and 13 are reported by the first violation.
+            Error::Validation(ref report) => report
+                .violations()
+                .first()
+                .map(|violation| violation.code())
+                .unwrap_or(1),
+            Error::Metering(_) => 7,
+            Error::Serialization(_) => 8,
+            Error::InvalidGasCostTable(_) => 9,
+            Error::CodeTooLarge { .. } => 10,
+            Error::StackLimiter(_) => 11,
+            Error::AlreadyMetered => 14,
+        }
+    }
+
+    /// ABI-encode the error as `(uint256 code, string message)`.
+    pub fn encode_revert(&self) -> Vec<u8> {
+        let message = self.to_string().into_bytes();
+        let padded_len = message.len().div_ceil(32) * 32;
+
+        let mut output = Vec::with_capacity(3 * 32 + padded_len);
+        output.extend_from_slice(&abi_word(self.code() as u64));
+        // Offset of the string data: right after the two head words.
+        output.extend_from_slice(&abi_word(64));
+        output.extend_from_slice(&abi_word(message.len() as u64));
+        output.extend_from_slice(&message);
+        output.resize(3 * 32 + padded_len, 0);
+        output
+    }
+}
+
+/// Encode a value as a big-endian 32 byte ABI word.
+fn abi_word(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    for (i, byte) in value.to_be_bytes().iter().enumerate() {
+        word[24 + i] = *byte;
+    }
+    word
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Validation(ref report) => write!(f, "Contract validation failed: {}", report),
+            Error::Metering(ref err) => write!(f, "Metering injection failed: {}", err),
+            Error::Serialization(ref err) => write!(f, "Serialization failed: {}", err),
+            Error::InvalidGasCostTable(ref msg) => write!(f, "Invalid gas cost table: {}", msg),
+            Error::CodeTooLarge { size, limit } => write!(
+                f,
+                "Contract size of {} bytes exceeds the limit of {} bytes",
+                size, limit
+            ),
+            Error::StackLimiter(ref msg) => write!(f, "Stack limiter injection failed: {}", msg),
+            Error::AlreadyMetered => write!(f, "Contract is already metered"),
+        }
+    }
+}
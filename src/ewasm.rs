@@ -0,0 +1,23 @@
+use crate::config::Config;
+use crate::{inject_metering, load_contract};
+
+#[no_mangle]
+pub extern "C" fn main() {
+    let code = ewasm_api::calldata_acquire();
+
+    let result = Config::embedded().and_then(|config| {
+        let pricing = &config.deployment_pricing;
+        pricing.check_code_size(code.len())?;
+        ewasm_api::consume_gas(pricing.code_cost(code.len()));
+
+        let module = load_contract(&code, &config)?;
+        ewasm_api::consume_gas(pricing.module_cost(&module));
+
+        inject_metering(module, &config)
+    });
+
+    match result {
+        Ok(output) => ewasm_api::finish_data(&output),
+        Err(err) => ewasm_api::revert_data(&err.encode_revert()),
+    }
+}
@@ -0,0 +1,52 @@
+use pwasm_utils::{rules::Set, Schedule};
+
+use crate::error::Error;
+
+/// The gas cost table selected at build time.
+static EMBEDDED_TABLE: &str = include_str!(concat!(env!("OUT_DIR"), "/gas_cost_table.toml"));
+
+/// Gas costs applied by the metering injection.
+///
+/// The table is a `pwasm_utils::Schedule`, see `gas_cost_table.toml` for the
+/// format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GasCostTable {
+    schedule: Schedule,
+}
+
+impl GasCostTable {
+    /// Load the table embedded at build time.
+    pub fn embedded() -> Result<Self, Error> {
+        GasCostTable::parse(EMBEDDED_TABLE)
+    }
+
+    /// Parse a gas cost table.
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let schedule =
+            Schedule::parse(source).map_err(|err| Error::InvalidGasCostTable(err.to_string()))?;
+        Ok(GasCostTable { schedule })
+    }
+
+    /// The table of a `pwasm_utils::Schedule` preset, e.g. `ewasm`.
+    pub fn preset(name: &str) -> Result<Self, Error> {
+        let schedule = Schedule::preset(name)
+            .map_err(|_| Error::InvalidGasCostTable(format!("unknown preset `{}`", name)))?;
+        Ok(GasCostTable { schedule })
+    }
+
+    /// The schedule of this table.
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// Build the metering rules described by this table.
+    pub fn to_rules(&self) -> Set {
+        self.schedule.to_rules()
+    }
+}
+
+impl From<Schedule> for GasCostTable {
+    fn from(schedule: Schedule) -> Self {
+        GasCostTable { schedule }
+    }
+}
@@ -1,84 +1,619 @@
+#[cfg(feature = "ewasm")]
 extern crate ewasm_api;
 extern crate libchisel;
 extern crate parity_wasm;
 extern crate pwasm_utils;
 
-use libchisel::{checkstartfunc::*, verifyexports::*, verifyimports::*};
-use libchisel::{ModulePreset, ModuleValidator};
+use parity_wasm::elements::Module;
+use pwasm_utils::rules::AlreadyMetered;
 
-fn validate_contract(module: &[u8]) -> bool {
-    let module = libchisel::Module::from_bytes(module);
-    if module.is_err() {
-        return false;
+pub mod config;
+mod determinism;
+mod error;
+#[cfg(feature = "ewasm")]
+mod ewasm;
+mod gas_table;
+mod limits;
+mod pricing;
+pub mod validation;
+
+pub use config::Config;
+pub use error::Error;
+pub use gas_table::GasCostTable;
+pub use limits::{Resource, ResourceLimits};
+pub use pricing::DeploymentPricing;
+
+fn load_contract(code: &[u8], config: &Config) -> Result<Module, Error> {
+    let mut module = match parity_wasm::deserialize_buffer(code) {
+        Ok(module) => module,
+        Err(_) => return Err(Error::Validation(validation::validate(code, config))),
+    };
+
+    // The optimizer panics on malformed modules, so only contracts which are
+    // valid except for their extra exports are stripped.
+    let report = validation::validate_module(code, &module, config);
+    let is_extra_export = |violation: &validation::Violation| {
+        matches!(*violation, validation::Violation::UnexpectedExport { .. })
+    };
+    let strip = config.strip_extra_exports
+        && !report.is_valid()
+        && report.violations().iter().all(is_extra_export);
+    if !strip {
+        return if report.is_valid() {
+            Ok(module)
+        } else {
+            Err(Error::Validation(report))
+        };
     }
-    let module = module.unwrap();
 
-    // Ensure no start functions is present.
-    if !CheckStartFunc::new(false).validate(&module).unwrap() {
-        return false;
+    // Only fails without an export section, which valid contracts have.
+    if pwasm_utils::optimize(&mut module, validation::ECI_EXPORTS.to_vec()).is_err() {
+        return Err(Error::Validation(report));
     }
+    let stripped = parity_wasm::serialize(module.clone()).map_err(Error::Serialization)?;
 
-    // Ensure only valid exports are present.
-    if !VerifyExports::with_preset("ewasm")
-        .unwrap()
-        .validate(&module)
-        .unwrap()
-    {
-        return false;
+    let report = validation::validate_module(&stripped, &module, config);
+    if !report.is_valid() {
+        return Err(Error::Validation(report));
     }
 
-    // Ensure only valid imports are used.
-    if !VerifyImports::with_preset("ewasm")
-        .unwrap()
-        .validate(&module)
-        .unwrap()
-    {
-        return false;
+    Ok(module)
+}
+
+fn inject_metering(module: Module, config: &Config) -> Result<Vec<u8>, Error> {
+    let rules = config
+        .gas_cost_table
+        .to_rules()
+        .with_already_metered(config.already_metered);
+
+    if pwasm_utils::is_metered(&module, &rules) {
+        match config.already_metered {
+            AlreadyMetered::Reject => return Err(Error::AlreadyMetered),
+            AlreadyMetered::PassThrough => {
+                return parity_wasm::serialize(module).map_err(Error::Serialization)
+            }
+            AlreadyMetered::Remeter => (),
+        }
     }
 
-    true
+    let mut result = pwasm_utils::inject_gas_counter(module, &rules).map_err(Error::Metering)?;
+
+    if let Some(stack_limit) = config.stack_limit {
+        result = pwasm_utils::stack_height::inject_limiter(result, stack_limit)
+            .map_err(|err| Error::StackLimiter(format!("{:?}", err)))?;
+    }
+
+    parity_wasm::serialize(result).map_err(Error::Serialization)
 }
 
-fn inject_metering(code: &[u8]) -> Result<Vec<u8>, parity_wasm::elements::Error> {
-    if !validate_contract(&code) {
-        return Err(parity_wasm::elements::Error::Other(
-            "Contract doesn't meet ECI/EEI restrictions.",
-        ));
+/// Validate a contract and inject gas metering into it.
+///
+/// This is the pipeline run by the ewasm `main` entry point, except that no
+/// gas is charged for the deployment.
+pub fn process(code: &[u8], config: &Config) -> Result<Vec<u8>, Error> {
+    config.deployment_pricing.check_code_size(code.len())?;
+
+    let module = load_contract(code, config)?;
+    inject_metering(module, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_wasm::elements::Instruction::*;
+    use parity_wasm::{builder, elements};
+
+    fn contract(instructions: Vec<elements::Instruction>) -> Vec<u8> {
+        let module = builder::module()
+            .function()
+            .signature()
+            .build()
+            .body()
+            .with_instructions(elements::Instructions::new(instructions))
+            .build()
+            .build()
+            .memory()
+            .build()
+            .export()
+            .field("main")
+            .internal()
+            .func(0)
+            .build()
+            .export()
+            .field("memory")
+            .internal()
+            .memory(0)
+            .build()
+            .build();
+
+        parity_wasm::serialize(module).expect("serialization to succeed")
     }
 
-    let module = parity_wasm::deserialize_buffer(&code)?;
+    /// A contract with `imports`, exporting `main` and `memory`.
+    ///
+    /// Imported functions refer to the function types `types`.
+    fn contract_with_imports(
+        types: Vec<elements::FunctionType>,
+        imports: Vec<elements::ImportEntry>,
+    ) -> Vec<u8> {
+        let imported_functions = imports
+            .iter()
+            .filter(|entry| matches!(*entry.external(), elements::External::Function(_)))
+            .count();
+        let mut module = builder::module();
+        for func_type in types {
+            module.push_signature(
+                builder::signature()
+                    .with_params(func_type.params().to_vec())
+                    .with_return_type(func_type.return_type())
+                    .build_sig(),
+            );
+        }
+        for entry in imports {
+            module = module.with_import(entry);
+        }
+        let module = module
+            .function()
+            .signature()
+            .build()
+            .body()
+            .build()
+            .build()
+            .memory()
+            .build()
+            .export()
+            .field("main")
+            .internal()
+            .func(imported_functions as u32)
+            .build()
+            .export()
+            .field("memory")
+            .internal()
+            .memory(0)
+            .build()
+            .build();
 
-    // TODO: extract values from the GasCostTable
+        parity_wasm::serialize(module).expect("serialization to succeed")
+    }
 
-    let memory_page_cost = 256 * 1024; // 256k gas for 1 page (64k) of memory
+    fn violations(code: &[u8]) -> Vec<validation::Violation> {
+        let err = process(code, &Config::embedded().unwrap()).unwrap_err();
+        match err {
+            Error::Validation(report) => report.violations().to_vec(),
+            _ => panic!("Expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn metered() {
+        let config = Config::embedded().unwrap();
+        let output = process(&contract(vec![Nop, End]), &config).unwrap();
+
+        let module = parity_wasm::deserialize_buffer::<Module>(&output).unwrap();
+        let import = &module.import_section().unwrap().entries()[0];
+        assert_eq!(import.module(), "ethereum");
+        assert_eq!(import.field(), "useGas");
+    }
+
+    #[test]
+    fn forbidden_float() {
+        let config = Config::embedded().unwrap();
+        let err = process(&contract(vec![F32Const(0), Drop, End]), &config).unwrap_err();
+        assert_eq!(err.code(), 13);
+
+        match err {
+            Error::Validation(report) => assert_eq!(
+                report.violations(),
+                &[validation::Violation::ForbiddenInstruction {
+                    function: 0,
+                    offset: 0,
+                    instruction: F32Const(0),
+                }][..]
+            ),
+            _ => panic!("Expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn mutable_global_import() {
+        let code = contract_with_imports(
+            vec![],
+            vec![elements::ImportEntry::new(
+                "ethereum".to_string(),
+                "gasLeft".to_string(),
+                elements::External::Global(elements::GlobalType::new(
+                    elements::ValueType::I64,
+                    true,
+                )),
+            )],
+        );
+
+        // Globals aren't part of the EEI either.
+        let violations = violations(&code);
+        assert_eq!(
+            violations,
+            vec![
+                validation::Violation::ImportKind {
+                    module: "ethereum".to_string(),
+                    field: "gasLeft".to_string(),
+                },
+                validation::Violation::MutableGlobalImport {
+                    module: "ethereum".to_string(),
+                    field: "gasLeft".to_string(),
+                },
+            ]
+        );
+        assert_eq!(violations[1].code(), 13);
+    }
+
+    #[test]
+    fn multiple_tables() {
+        let module = parity_wasm::deserialize_buffer::<Module>(&contract(vec![End])).unwrap();
+        let module = builder::from_module(module)
+            .table()
+            .with_min(1)
+            .build()
+            .table()
+            .with_min(1)
+            .build()
+            .build();
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let err = process(&code, &Config::embedded().unwrap()).unwrap_err();
+        assert_eq!(err.code(), 13);
+        assert_eq!(
+            violations(&code),
+            vec![validation::Violation::MultipleTables(2)]
+        );
+    }
+
+    #[test]
+    fn multiple_memories() {
+        let module = parity_wasm::deserialize_buffer::<Module>(&contract(vec![End])).unwrap();
+        let module = builder::from_module(module).memory().build().build();
+        let code = parity_wasm::serialize(module).unwrap();
 
-    let config = pwasm_utils::rules::Set::default()
-        .with_forbidden_floats() // Reject floating point opreations.
-        .with_grow_cost(memory_page_cost);
+        let err = process(&code, &Config::embedded().unwrap()).unwrap_err();
+        assert_eq!(err.code(), 13);
+        assert_eq!(
+            violations(&code),
+            vec![validation::Violation::MultipleMemories(2)]
+        );
+    }
+
+    #[test]
+    fn import_violations() {
+        let function = |module: &str, field: &str| {
+            elements::ImportEntry::new(
+                module.to_string(),
+                field.to_string(),
+                elements::External::Function(0),
+            )
+        };
 
-    let result = match pwasm_utils::inject_gas_counter(module, &config) {
-        Ok(output) => output,
-        Err(_) => {
-            return Err(parity_wasm::elements::Error::Other(
-                "Metering injection failed.",
+        // The type 0 is `() -> ()`, the signature of `main`.
+        let cases = vec![
+            (
+                function("env", "useGas"),
+                validation::Violation::ImportNamespace {
+                    module: "env".to_string(),
+                    field: "useGas".to_string(),
+                },
+            ),
+            (
+                elements::ImportEntry::new(
+                    "ethereum".to_string(),
+                    "table".to_string(),
+                    elements::External::Table(elements::TableType::new(1, None)),
+                ),
+                validation::Violation::ImportKind {
+                    module: "ethereum".to_string(),
+                    field: "table".to_string(),
+                },
+            ),
+            (
+                function("ethereum", "print"),
+                validation::Violation::UnknownImport {
+                    field: "print".to_string(),
+                },
+            ),
+            (
+                function("ethereum", "useGas"),
+                validation::Violation::ImportSignature {
+                    field: "useGas".to_string(),
+                },
+            ),
+        ];
+        for (entry, violation) in cases {
+            let code = contract_with_imports(vec![], vec![entry]);
+            let err = process(&code, &Config::embedded().unwrap()).unwrap_err();
+            assert_eq!(err.code(), 4);
+            assert_eq!(violations(&code), vec![violation]);
+        }
+
+        // Every function of the EEI can be imported with its signature.
+        let mut types = Vec::new();
+        let mut imports = Vec::new();
+        for &(field, params, return_type) in validation::EEI_FUNCTIONS {
+            // The builder merges equal types, so they must be unique.
+            let func_type = elements::FunctionType::new(params.to_vec(), return_type);
+            let type_ref = match types.iter().position(|existing| *existing == func_type) {
+                Some(type_ref) => type_ref,
+                None => {
+                    types.push(func_type);
+                    types.len() - 1
+                }
+            };
+            imports.push(elements::ImportEntry::new(
+                "ethereum".to_string(),
+                field.to_string(),
+                elements::External::Function(type_ref as u32),
             ));
         }
-    };
+        let code = contract_with_imports(types, imports);
+        assert!(process(&code, &Config::embedded().unwrap()).is_ok());
+    }
 
-    parity_wasm::serialize(result)
-}
+    #[test]
+    fn unexpected_export() {
+        let code = contract(vec![End]);
+        let mut module = parity_wasm::deserialize_buffer::<Module>(&code).unwrap();
+        for section in module.sections_mut() {
+            if let elements::Section::Export(ref mut exports) = *section {
+                exports.entries_mut().push(elements::ExportEntry::new(
+                    "other".to_string(),
+                    elements::Internal::Function(0),
+                ));
+            }
+        }
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let report = validation::validate(&code, &Config::embedded().unwrap());
+        assert_eq!(
+            report.violations(),
+            &[validation::Violation::UnexpectedExport {
+                field: "other".to_string()
+            }][..]
+        );
+    }
+
+    #[test]
+    fn main_signature() {
+        let module = builder::module()
+            .function()
+            .signature()
+            .param()
+            .i32()
+            .build()
+            .body()
+            .build()
+            .build()
+            .memory()
+            .build()
+            .export()
+            .field("main")
+            .internal()
+            .func(0)
+            .build()
+            .export()
+            .field("memory")
+            .internal()
+            .memory(0)
+            .build()
+            .build();
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let report = validation::validate(&code, &Config::embedded().unwrap());
+        assert_eq!(
+            report.violations()[0],
+            validation::Violation::ExportSignature {
+                field: "main".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn strip_extra_exports() {
+        let code = contract(vec![End]);
+        let mut module = parity_wasm::deserialize_buffer::<Module>(&code).unwrap();
+        for section in module.sections_mut() {
+            if let elements::Section::Export(ref mut exports) = *section {
+                exports.entries_mut().push(elements::ExportEntry::new(
+                    "other".to_string(),
+                    elements::Internal::Function(0),
+                ));
+            }
+        }
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let mut config = Config::embedded().unwrap();
+        assert_eq!(process(&code, &config).unwrap_err().code(), 3);
+
+        config.strip_extra_exports = true;
+        let output = process(&code, &config).unwrap();
+        let module = parity_wasm::deserialize_buffer::<Module>(&output).unwrap();
+        let fields: Vec<_> = module
+            .export_section()
+            .unwrap()
+            .entries()
+            .iter()
+            .map(|entry| entry.field())
+            .collect();
+        assert_eq!(fields, vec!["main", "memory"]);
+    }
+
+    #[test]
+    fn strip_extra_exports_valid() {
+        // The unused function is only removed together with extra exports.
+        let module = builder::module()
+            .function()
+            .signature()
+            .build()
+            .body()
+            .build()
+            .build()
+            .function()
+            .signature()
+            .build()
+            .body()
+            .build()
+            .build()
+            .memory()
+            .build()
+            .export()
+            .field("main")
+            .internal()
+            .func(0)
+            .build()
+            .export()
+            .field("memory")
+            .internal()
+            .memory(0)
+            .build()
+            .build();
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let mut config = Config::embedded().unwrap();
+        config.strip_extra_exports = true;
+        let output = process(&code, &config).unwrap();
+        let module = parity_wasm::deserialize_buffer::<Module>(&output).unwrap();
+        assert_eq!(module.function_section().unwrap().entries().len(), 2);
+    }
+
+    #[test]
+    fn strip_extra_exports_malformed() {
+        // `main` refers to a function which doesn't exist.
+        let module = builder::module()
+            .function()
+            .signature()
+            .build()
+            .body()
+            .build()
+            .build()
+            .memory()
+            .build()
+            .export()
+            .field("main")
+            .internal()
+            .func(7)
+            .build()
+            .export()
+            .field("memory")
+            .internal()
+            .memory(0)
+            .build()
+            .export()
+            .field("other")
+            .internal()
+            .func(0)
+            .build()
+            .build();
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let mut config = Config::embedded().unwrap();
+        config.strip_extra_exports = true;
+        match process(&code, &config).unwrap_err() {
+            Error::Validation(report) => assert_eq!(
+                report.violations(),
+                &[
+                    validation::Violation::ExportSignature {
+                        field: "main".to_string()
+                    },
+                    validation::Violation::UnexpectedExport {
+                        field: "other".to_string()
+                    },
+                ][..]
+            ),
+            _ => panic!("Expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn stack_limited() {
+        let mut config = Config::embedded().unwrap();
+        config.stack_limit = Some(1024);
+
+        let output = process(&contract(vec![Nop, End]), &config).unwrap();
+
+        let module = parity_wasm::deserialize_buffer::<Module>(&output).unwrap();
+        assert_eq!(module.global_section().unwrap().entries().len(), 1);
+        assert!(validation::validate(&output, &config).is_valid());
+    }
+
+    #[test]
+    fn resource_limits() {
+        let mut config = Config::embedded().unwrap();
+        config.resource_limits.max_memory_pages = Some(16);
+
+        let report = validation::validate(&contract(vec![End]), &config);
+        assert_eq!(
+            report.violations(),
+            &[validation::Violation::ResourceLimit {
+                resource: Resource::MaximumMemoryPages,
+                value: 65536,
+                limit: 16,
+            }][..]
+        );
+    }
+
+    #[test]
+    fn gas_cost_table_round_trip() {
+        let table = GasCostTable::embedded().unwrap();
+        let source = table.schedule().to_string();
+        assert_eq!(GasCostTable::parse(&source).unwrap(), table);
+    }
+
+    #[test]
+    fn gas_cost_table_preset() {
+        let table = GasCostTable::parse("preset = \"strict-integer\"").unwrap();
+        assert_eq!(table, GasCostTable::preset("strict-integer").unwrap());
+
+        let mut config = Config::embedded().unwrap();
+        config.gas_cost_table = table;
+        let err = process(
+            &contract(vec![I32Const(1), GrowMemory(0), Drop, End]),
+            &config,
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), 13);
+
+        assert_eq!(GasCostTable::preset("evm").unwrap_err().code(), 9);
+    }
+
+    #[test]
+    fn code_too_large() {
+        let mut config = Config::embedded().unwrap();
+        config.deployment_pricing.max_code_size = Some(8);
+
+        let err = process(&contract(vec![End]), &config).unwrap_err();
+        assert_eq!(err.code(), 10);
+    }
+
+    #[test]
+    fn already_metered() {
+        let mut config = Config::embedded().unwrap();
+        let metered = process(&contract(vec![Nop, End]), &config).unwrap();
+
+        let err = process(&metered, &config).unwrap_err();
+        assert_eq!(err.code(), 14);
+
+        config.already_metered = AlreadyMetered::PassThrough;
+        assert_eq!(process(&metered, &config).unwrap(), metered);
+
+        config.already_metered = AlreadyMetered::Remeter;
+        assert_eq!(process(&metered, &config).unwrap(), metered);
+    }
 
-#[no_mangle]
-pub extern "C" fn main() {
-    let code = ewasm_api::calldata_acquire();
+    #[test]
+    fn already_metered_stack_limited() {
+        let mut config = Config::embedded().unwrap();
+        config.stack_limit = Some(1024);
+        let limited = process(&contract(vec![I32Const(1), Drop, End]), &config).unwrap();
 
-    // TODO: make this a configuration feature
-    let total_cost = 32 * code.len();
-    ewasm_api::consume_gas(total_cost as u64);
+        let err = process(&limited, &config).unwrap_err();
+        assert_eq!(err.code(), 14);
 
-    match inject_metering(&code) {
-        Ok(output) => ewasm_api::finish_data(&output),
-        // TODO: return reason in revert
-        Err(_) => ewasm_api::revert(),
+        config.already_metered = AlreadyMetered::PassThrough;
+        assert_eq!(process(&limited, &config).unwrap(), limited);
     }
 }
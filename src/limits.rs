@@ -0,0 +1,176 @@
+use std::fmt;
+
+use parity_wasm::elements::{Module, Type};
+
+use crate::validation::{Report, Violation};
+
+/// Largest number of memory pages a module can address.
+const MAX_MEMORY_PAGES: u64 = 65536;
+
+/// Resources of a contract which can be limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Number of functions defined by the contract.
+    Functions,
+    /// Number of globals defined by the contract.
+    Globals,
+    /// Number of locals (including parameters) of the given function.
+    Locals(u32),
+    /// Initial size of the table.
+    TableSize,
+    /// Initial number of memory pages.
+    InitialMemoryPages,
+    /// Maximum number of memory pages.
+    MaximumMemoryPages,
+    /// Total size of all data segments in bytes.
+    DataSize,
+    /// Number of instructions in the body of the given function.
+    FunctionBodySize(u32),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Resource::Functions => write!(f, "number of functions"),
+            Resource::Globals => write!(f, "number of globals"),
+            Resource::Locals(func) => write!(f, "number of locals of function {}", func),
+            Resource::TableSize => write!(f, "table size"),
+            Resource::InitialMemoryPages => write!(f, "initial memory pages"),
+            Resource::MaximumMemoryPages => write!(f, "maximum memory pages"),
+            Resource::DataSize => write!(f, "size of data segments"),
+            Resource::FunctionBodySize(func) => write!(f, "body size of function {}", func),
+        }
+    }
+}
+
+/// Caps on the resources used by a contract.
+///
+/// `None` means the resource is not limited. Function indices reported in
+/// violations are relative to the defined functions, i.e. exclude imports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub max_functions: Option<u64>,
+    pub max_globals: Option<u64>,
+    pub max_locals: Option<u64>,
+    pub max_table_size: Option<u64>,
+    pub max_initial_memory_pages: Option<u64>,
+    /// A memory without a declared maximum counts as the largest possible size.
+    pub max_memory_pages: Option<u64>,
+    pub max_data_size: Option<u64>,
+    /// Limit on the number of instructions in a function body.
+    pub max_function_body_size: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Limits which accept any contract.
+    pub const UNLIMITED: ResourceLimits = ResourceLimits {
+        max_functions: None,
+        max_globals: None,
+        max_locals: None,
+        max_table_size: None,
+        max_initial_memory_pages: None,
+        max_memory_pages: None,
+        max_data_size: None,
+        max_function_body_size: None,
+    };
+
+    /// Report every resource of the module exceeding its limit.
+    pub(crate) fn check(&self, module: &Module, report: &mut Report) {
+        let mut check = |resource: Resource, value: u64, limit: Option<u64>| match limit {
+            Some(limit) if value > limit => report.push(Violation::ResourceLimit {
+                resource,
+                value,
+                limit,
+            }),
+            _ => {}
+        };
+
+        let functions = module
+            .function_section()
+            .map(|section| section.entries())
+            .unwrap_or(&[]);
+        check(
+            Resource::Functions,
+            functions.len() as u64,
+            self.max_functions,
+        );
+
+        let globals = module
+            .global_section()
+            .map(|section| section.entries().len())
+            .unwrap_or(0);
+        check(Resource::Globals, globals as u64, self.max_globals);
+
+        let types = module
+            .type_section()
+            .map(|section| section.types())
+            .unwrap_or(&[]);
+        let bodies = module
+            .code_section()
+            .map(|section| section.bodies())
+            .unwrap_or(&[]);
+        for (index, body) in bodies.iter().enumerate() {
+            let params = functions
+                .get(index)
+                .and_then(|func| types.get(func.type_ref() as usize))
+                .map(|Type::Function(func_type)| func_type.params().len())
+                .unwrap_or(0);
+            let locals = body
+                .locals()
+                .iter()
+                .map(|local| local.count() as u64)
+                .sum::<u64>();
+            check(
+                Resource::Locals(index as u32),
+                params as u64 + locals,
+                self.max_locals,
+            );
+            check(
+                Resource::FunctionBodySize(index as u32),
+                body.code().elements().len() as u64,
+                self.max_function_body_size,
+            );
+        }
+
+        if let Some(section) = module.table_section() {
+            for table in section.entries() {
+                check(
+                    Resource::TableSize,
+                    table.limits().initial() as u64,
+                    self.max_table_size,
+                );
+            }
+        }
+
+        if let Some(section) = module.memory_section() {
+            for memory in section.entries() {
+                check(
+                    Resource::InitialMemoryPages,
+                    memory.limits().initial() as u64,
+                    self.max_initial_memory_pages,
+                );
+                check(
+                    Resource::MaximumMemoryPages,
+                    memory
+                        .limits()
+                        .maximum()
+                        .map(|max| max as u64)
+                        .unwrap_or(MAX_MEMORY_PAGES),
+                    self.max_memory_pages,
+                );
+            }
+        }
+
+        let data_size = module
+            .data_section()
+            .map(|section| {
+                section
+                    .entries()
+                    .iter()
+                    .map(|segment| segment.value().len() as u64)
+                    .sum()
+            })
+            .unwrap_or(0);
+        check(Resource::DataSize, data_size, self.max_data_size);
+    }
+}
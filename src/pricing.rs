@@ -0,0 +1,62 @@
+use parity_wasm::elements::Module;
+
+use crate::error::Error;
+
+/// Pricing of contract deployment.
+///
+/// The cost depending on the code size is charged before the contract is
+/// parsed, the surcharges depending on its contents afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeploymentPricing {
+    /// Fixed cost of every deployment.
+    pub base: u64,
+    /// Cost of every byte of the contract.
+    pub per_byte: u64,
+    /// Cost of every function defined by the contract.
+    pub per_function: u64,
+    /// Cost of every instruction in the function bodies of the contract.
+    pub per_instruction: u64,
+    /// Contracts larger than this many bytes are rejected.
+    pub max_code_size: Option<usize>,
+}
+
+impl DeploymentPricing {
+    /// Ensure the code size is within the limit.
+    pub fn check_code_size(&self, code_size: usize) -> Result<(), Error> {
+        match self.max_code_size {
+            Some(limit) if code_size > limit => Err(Error::CodeTooLarge {
+                size: code_size,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Cost to be charged for the raw code.
+    pub fn code_cost(&self, code_size: usize) -> u64 {
+        self.base
+            .saturating_add(self.per_byte.saturating_mul(code_size as u64))
+    }
+
+    /// Surcharge to be charged for the contents of the parsed module.
+    pub fn module_cost(&self, module: &Module) -> u64 {
+        let functions = module
+            .function_section()
+            .map(|section| section.entries().len())
+            .unwrap_or(0);
+        let instructions = module
+            .code_section()
+            .map(|section| {
+                section
+                    .bodies()
+                    .iter()
+                    .map(|body| body.code().elements().len())
+                    .sum()
+            })
+            .unwrap_or(0);
+
+        self.per_function
+            .saturating_mul(functions as u64)
+            .saturating_add(self.per_instruction.saturating_mul(instructions as u64))
+    }
+}
@@ -0,0 +1,480 @@
+use std::fmt;
+
+use libchisel::{checkstartfunc::*, verifyexports::*, verifyimports::*};
+use libchisel::{ModulePreset, ModuleValidator};
+use parity_wasm::elements::{
+    External, FunctionType, Instruction, Internal, Module, Type, ValueType,
+};
+
+use crate::config::Config;
+use crate::determinism;
+use crate::limits::Resource;
+
+/// The functions provided by the EEI, with their parameters and return type.
+pub(crate) const EEI_FUNCTIONS: &[(&str, &[ValueType], Option<ValueType>)] = {
+    use parity_wasm::elements::ValueType::*;
+    &[
+        ("useGas", &[I64], None),
+        ("getAddress", &[I32], None),
+        ("getExternalBalance", &[I32, I32], None),
+        ("getBlockHash", &[I64, I32], Some(I32)),
+        ("call", &[I64, I32, I32, I32, I32], Some(I32)),
+        ("callDataCopy", &[I32, I32, I32], None),
+        ("getCallDataSize", &[], Some(I32)),
+        ("callCode", &[I64, I32, I32, I32, I32], Some(I32)),
+        ("callDelegate", &[I64, I32, I32, I32], Some(I32)),
+        ("callStatic", &[I64, I32, I32, I32], Some(I32)),
+        ("storageStore", &[I32, I32], None),
+        ("storageLoad", &[I32, I32], None),
+        ("getCaller", &[I32], None),
+        ("getCallValue", &[I32], None),
+        ("codeCopy", &[I32, I32, I32], None),
+        ("getCodeSize", &[], Some(I32)),
+        ("getBlockCoinbase", &[I32], None),
+        ("create", &[I32, I32, I32, I32], Some(I32)),
+        ("getBlockDifficulty", &[I32], None),
+        ("externalCodeCopy", &[I32, I32, I32, I32], None),
+        ("getExternalCodeSize", &[I32], Some(I32)),
+        ("getGasLeft", &[], Some(I64)),
+        ("getBlockGasLimit", &[], Some(I64)),
+        ("getTxGasPrice", &[I32], None),
+        ("log", &[I32, I32, I32, I32, I32, I32, I32], None),
+        ("getBlockNumber", &[], Some(I64)),
+        ("getTxOrigin", &[I32], None),
+        ("finish", &[I32, I32], None),
+        ("revert", &[I32, I32], None),
+        ("getReturnDataSize", &[], Some(I32)),
+        ("returnDataCopy", &[I32, I32, I32], None),
+        ("selfDestruct", &[I32], None),
+        ("getBlockTimestamp", &[], Some(I64)),
+    ]
+};
+
+/// The namespace of the EEI imports.
+const EEI_MODULE: &str = "ethereum";
+
+/// The exports required by the ECI.
+pub(crate) const ECI_EXPORTS: &[&str] = &["main", "memory"];
+
+/// The checks run on a contract.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    StartFunction,
+    Exports,
+    Imports,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Check::StartFunction => write!(f, "start function"),
+            Check::Exports => write!(f, "exports"),
+            Check::Imports => write!(f, "imports"),
+        }
+    }
+}
+
+/// A rule broken by a contract.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// The contract is not a valid WebAssembly module.
+    Malformed,
+    /// The contract has a start function.
+    StartFunction,
+    /// An import is from a namespace other than the EEI.
+    ImportNamespace { module: String, field: String },
+    /// An import is not a function.
+    ImportKind { module: String, field: String },
+    /// An imported function is not part of the EEI.
+    UnknownImport { field: String },
+    /// An imported function doesn't match the signature defined by the EEI.
+    ImportSignature { field: String },
+    /// A required export is missing.
+    MissingExport { field: String },
+    /// An export is of the wrong kind, e.g. `main` is not a function.
+    ExportKind { field: String },
+    /// An exported function doesn't have the signature required by the ECI.
+    ExportSignature { field: String },
+    /// An export not allowed by the ECI.
+    UnexpectedExport { field: String },
+    /// An imported global is mutable.
+    MutableGlobalImport { module: String, field: String },
+    /// The contract has more than one table.
+    MultipleTables(usize),
+    /// The contract has more than one memory.
+    MultipleMemories(usize),
+    /// An instruction forbidden by the gas cost table, e.g. a float operation.
+    ///
+    /// The function index excludes imports and the offset is the index of
+    /// the instruction in the function body.
+    ForbiddenInstruction {
+        function: u32,
+        offset: u32,
+        instruction: Instruction,
+    },
+    /// A resource of the contract exceeds its limit.
+    ResourceLimit {
+        resource: Resource,
+        value: u64,
+        limit: u64,
+    },
+    /// A validator rejected the contract without a more specific violation.
+    Rejected(Check),
+    /// A validator failed to run.
+    ValidatorFailed { check: Check, message: String },
+}
+
+impl Violation {
+    /// Stable error code of the violation, see `Error::code`.
+    pub fn code(&self) -> u32 {
+        match *self {
+            Violation::Malformed => 1,
+            Violation::StartFunction | Violation::Rejected(Check::StartFunction) => 2,
+            Violation::MissingExport { .. }
+            | Violation::ExportKind { .. }
+            | Violation::ExportSignature { .. }
+            | Violation::UnexpectedExport { .. }
+            | Violation::Rejected(Check::Exports) => 3,
+            Violation::ImportNamespace { .. }
+            | Violation::ImportKind { .. }
+            | Violation::UnknownImport { .. }
+            | Violation::ImportSignature { .. }
+            | Violation::Rejected(Check::Imports) => 4,
+            Violation::ValidatorFailed { .. } => 5,
+            Violation::ResourceLimit { .. } => 12,
+            Violation::MutableGlobalImport { .. }
+            | Violation::MultipleTables(_)
+            | Violation::MultipleMemories(_)
+            | Violation::ForbiddenInstruction { .. } => 13,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::Malformed => write!(f, "Contract is not a valid WebAssembly module"),
+            Violation::StartFunction => write!(f, "Contract must not have a start function"),
+            Violation::ImportNamespace {
+                ref module,
+                ref field,
+            } => write!(
+                f,
+                "Import `{}.{}` is not from the `{}` namespace",
+                module, field, EEI_MODULE
+            ),
+            Violation::ImportKind {
+                ref module,
+                ref field,
+            } => write!(f, "Import `{}.{}` must be a function", module, field),
+            Violation::UnknownImport { ref field } => {
+                write!(f, "Import `{}` is not part of the EEI", field)
+            }
+            Violation::ImportSignature { ref field } => write!(
+                f,
+                "Import `{}` doesn't match the signature defined by the EEI",
+                field
+            ),
+            Violation::MissingExport { ref field } => {
+                write!(f, "Export `{}` is required by the ECI", field)
+            }
+            Violation::ExportKind { ref field } => {
+                write!(f, "Export `{}` is of the wrong kind", field)
+            }
+            Violation::ExportSignature { ref field } => {
+                write!(f, "Export `{}` must have the signature `() -> ()`", field)
+            }
+            Violation::UnexpectedExport { ref field } => {
+                write!(f, "Export `{}` is not allowed by the ECI", field)
+            }
+            Violation::MutableGlobalImport {
+                ref module,
+                ref field,
+            } => write!(
+                f,
+                "Imported global `{}.{}` must not be mutable",
+                module, field
+            ),
+            Violation::MultipleTables(count) => {
+                write!(f, "Contract has {} tables, at most one is allowed", count)
+            }
+            Violation::MultipleMemories(count) => {
+                write!(f, "Contract has {} memories, at most one is allowed", count)
+            }
+            Violation::ForbiddenInstruction {
+                function,
+                offset,
+                ref instruction,
+            } => write!(
+                f,
+                "Instruction `{}` at offset {} of function {} is forbidden",
+                instruction, offset, function
+            ),
+            Violation::ResourceLimit {
+                resource,
+                value,
+                limit,
+            } => write!(
+                f,
+                "The {} of {} exceeds the limit of {}",
+                resource, value, limit
+            ),
+            Violation::Rejected(check) => write!(f, "Contract {} rejected by the validator", check),
+            Violation::ValidatorFailed { check, ref message } => {
+                write!(f, "Validator of {} failed: {}", check, message)
+            }
+        }
+    }
+}
+
+/// Result of validating a contract.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    violations: Vec<Violation>,
+}
+
+impl Report {
+    /// Returns true if the contract has no violations.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// All violations found, in the order of the checks.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub(crate) fn push(&mut self, violation: Violation) {
+        self.violations.push(violation);
+    }
+
+    /// Record the outcome of a libchisel validator for `check`.
+    ///
+    /// A rejection is only recorded if no specific violation for that check was found.
+    fn push_validator_result(&mut self, check: Check, result: Result<bool, String>) {
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                if !self
+                    .violations
+                    .iter()
+                    .any(|v| v.code() == Violation::Rejected(check).code())
+                {
+                    self.push(Violation::Rejected(check));
+                }
+            }
+            Err(message) => self.push(Violation::ValidatorFailed { check, message }),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, violation) in self.violations.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run all checks on a contract and report every violation found.
+pub fn validate(code: &[u8], config: &Config) -> Report {
+    match parity_wasm::deserialize_buffer(code) {
+        Ok(module) => validate_module(code, &module, config),
+        Err(_) => {
+            let mut report = Report::default();
+            report.push(Violation::Malformed);
+            report
+        }
+    }
+}
+
+/// Run all checks on a contract which was already deserialized.
+pub(crate) fn validate_module(code: &[u8], module: &Module, config: &Config) -> Report {
+    let mut report = Report::default();
+
+    let chisel_module = match libchisel::Module::from_bytes(code) {
+        Ok(chisel_module) => chisel_module,
+        Err(_) => {
+            report.push(Violation::Malformed);
+            return report;
+        }
+    };
+
+    // Ensure no start functions is present.
+    if module.start_section().is_some() {
+        report.push(Violation::StartFunction);
+    }
+    report.push_validator_result(
+        Check::StartFunction,
+        CheckStartFunc::new(false)
+            .validate(&chisel_module)
+            .map_err(|err| format!("{:?}", err)),
+    );
+
+    // Ensure only valid exports are present.
+    check_exports(module, &mut report);
+    report.push_validator_result(
+        Check::Exports,
+        VerifyExports::with_preset("ewasm")
+            .map_err(|_| "Unknown preset `ewasm`".to_string())
+            .and_then(|validator| {
+                validator
+                    .validate(&chisel_module)
+                    .map_err(|err| format!("{:?}", err))
+            }),
+    );
+
+    // Ensure only valid imports are used.
+    check_imports(module, &mut report);
+    report.push_validator_result(
+        Check::Imports,
+        VerifyImports::with_preset("ewasm")
+            .map_err(|_| "Unknown preset `ewasm`".to_string())
+            .and_then(|validator| {
+                validator
+                    .validate(&chisel_module)
+                    .map_err(|err| format!("{:?}", err))
+            }),
+    );
+
+    // Ensure no non-deterministic or unsupported constructs are used.
+    determinism::check(module, &config.gas_cost_table.to_rules(), &mut report);
+
+    // Ensure the resources used are within the limits.
+    config.resource_limits.check(module, &mut report);
+
+    report
+}
+
+fn check_exports(module: &Module, report: &mut Report) {
+    let entries = module
+        .export_section()
+        .map(|section| section.entries())
+        .unwrap_or(&[]);
+
+    for field in ECI_EXPORTS {
+        if !entries.iter().any(|entry| entry.field() == *field) {
+            report.push(Violation::MissingExport {
+                field: field.to_string(),
+            });
+        }
+    }
+
+    for entry in entries {
+        let valid_kind = match (entry.field(), entry.internal()) {
+            ("main", &Internal::Function(func_index)) => {
+                let valid_signature = function_type(module, func_index)
+                    .map(|func_type| {
+                        func_type.params().is_empty() && func_type.return_type().is_none()
+                    })
+                    .unwrap_or(false);
+                if !valid_signature {
+                    report.push(Violation::ExportSignature {
+                        field: entry.field().to_string(),
+                    });
+                }
+                true
+            }
+            ("memory", &Internal::Memory(_)) => true,
+            ("main", _) | ("memory", _) => false,
+            _ => {
+                report.push(Violation::UnexpectedExport {
+                    field: entry.field().to_string(),
+                });
+                continue;
+            }
+        };
+        if !valid_kind {
+            report.push(Violation::ExportKind {
+                field: entry.field().to_string(),
+            });
+        }
+    }
+}
+
+/// Look up the type of a function in the function index space.
+fn function_type(module: &Module, func_index: u32) -> Option<&FunctionType> {
+    let imported_types = module
+        .import_section()
+        .map(|section| section.entries())
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|entry| match *entry.external() {
+            External::Function(type_ref) => Some(type_ref),
+            _ => None,
+        });
+    let defined_types = module
+        .function_section()
+        .map(|section| section.entries())
+        .unwrap_or(&[])
+        .iter()
+        .map(|func| func.type_ref());
+
+    let type_ref = imported_types
+        .chain(defined_types)
+        .nth(func_index as usize)?;
+    match module.type_section()?.types().get(type_ref as usize)? {
+        Type::Function(func_type) => Some(func_type),
+    }
+}
+
+fn check_imports(module: &Module, report: &mut Report) {
+    let entries = module
+        .import_section()
+        .map(|section| section.entries())
+        .unwrap_or(&[]);
+    let types = module
+        .type_section()
+        .map(|section| section.types())
+        .unwrap_or(&[]);
+
+    for entry in entries {
+        if entry.module() != EEI_MODULE {
+            report.push(Violation::ImportNamespace {
+                module: entry.module().to_string(),
+                field: entry.field().to_string(),
+            });
+            continue;
+        }
+
+        let type_ref = match *entry.external() {
+            External::Function(type_ref) => type_ref,
+            _ => {
+                report.push(Violation::ImportKind {
+                    module: entry.module().to_string(),
+                    field: entry.field().to_string(),
+                });
+                continue;
+            }
+        };
+
+        let (params, return_type) = match EEI_FUNCTIONS
+            .iter()
+            .find(|(name, _, _)| *name == entry.field())
+        {
+            Some(&(_, params, return_type)) => (params, return_type),
+            None => {
+                report.push(Violation::UnknownImport {
+                    field: entry.field().to_string(),
+                });
+                continue;
+            }
+        };
+
+        let matches = match types.get(type_ref as usize) {
+            Some(Type::Function(func_type)) => {
+                func_type.params() == params && func_type.return_type() == return_type
+            }
+            None => false,
+        };
+        if !matches {
+            report.push(Violation::ImportSignature {
+                field: entry.field().to_string(),
+            });
+        }
+    }
+}
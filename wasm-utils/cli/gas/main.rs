@@ -3,28 +3,59 @@ extern crate pwasm_utils as utils;
 extern crate pwasm_utils_cli as logger;
 
 use std::env;
+use std::fs;
 
 fn main() {
 	logger::init_log();
 
 	let args = env::args().collect::<Vec<_>>();
-	if args.len() != 3 {
-		println!("Usage: {} input_file.wasm output_file.wasm", args[0]);
-		return;
-	}
-
-	let memory_page_cost = 256 * 1024; // 256k gas for 1 page (64k) of memory
-
-	let config = pwasm_utils::rules::Set::default()
-		.with_forbidden_floats() // Reject floating point opreations.
-		.with_grow_cost(memory_page_cost);
+	let usage = || {
+		println!("Usage: {} input_file.wasm output_file.wasm [schedule.toml | --preset <name>]", args[0]);
+		println!("Presets: {}", utils::Schedule::PRESETS.join(", "));
+	};
+
+	let config = match (args.len(), args.get(3).map(|arg| arg.as_str())) {
+		(5, Some("--preset")) => match pwasm_utils::rules::Set::with_preset(&args[4]) {
+			Ok(rules) => rules,
+			Err(()) => {
+				eprintln!("Unknown preset {}", args[4]);
+				usage();
+				::std::process::exit(1);
+			},
+		},
+		(4, Some(path)) => {
+			let source = fs::read_to_string(path).expect("Schedule to be readable");
+			match utils::Schedule::parse(&source) {
+				Ok(schedule) => schedule.to_rules(),
+				Err(err) => {
+					eprintln!("Invalid schedule {}: {}", path, err);
+					::std::process::exit(1);
+				},
+			}
+		},
+		(3, None) => {
+			let memory_page_cost = 256 * 1024; // 256k gas for 1 page (64k) of memory
+
+			pwasm_utils::rules::Set::default()
+				.with_forbidden_floats() // Reject floating point opreations.
+				.with_grow_cost(memory_page_cost)
+		},
+		_ => {
+			usage();
+			return;
+		},
+	};
 
 	// Loading module
 	let module = parity_wasm::deserialize_file(&args[1]).expect("Module deserialization to succeed");
 
-	let result = utils::inject_gas_counter(
-		module, &config
-	).expect("Failed to inject gas. Some forbidden opcodes?");
+	let result = match utils::inject_gas_counter(module, &config) {
+		Ok(result) => result,
+		Err(err) => {
+			eprintln!("Failed to inject gas: {}", err);
+			::std::process::exit(1);
+		},
+	};
 
 	parity_wasm::serialize_to_file(&args[2], result).expect("Module serialization to succeed")
 }
@@ -1,4 +1,4 @@
-use std;
+use crate::std;
 use super::{
 	CREATE_SYMBOL,
 	CALL_SYMBOL,
@@ -1,21 +1,23 @@
-use std::string::String;
-use std::vec::Vec;
-use std::borrow::ToOwned;
+use crate::std::string::String;
+use crate::std::vec::Vec;
+use crate::std::borrow::ToOwned;
 
 use parity_wasm::{elements, builder};
 use crate::optimizer::{import_section, export_section};
+use crate::remap::shift_function_indices;
 use byteorder::{LittleEndian, ByteOrder};
 
 type Insertion = (usize, u32, u32, String);
 
+/// Rewires calls of the replaced functions to their imports.
+///
+/// Expects the function indices to be already shifted past the inserted imports.
 pub fn update_call_index(instructions: &mut elements::Instructions, original_imports: usize, inserts: &[Insertion]) {
 	use parity_wasm::elements::Instruction::*;
 	for instruction in instructions.elements_mut().iter_mut() {
 		if let &mut Call(ref mut call_index) = instruction {
-			if let Some(pos) = inserts.iter().position(|x| x.1 == *call_index) {
+			if let Some(pos) = inserts.iter().position(|x| x.1 + inserts.len() as u32 == *call_index) {
 				*call_index = (original_imports + pos) as u32;
-			} else if *call_index as usize > original_imports {
-				*call_index += inserts.len() as u32;
 			}
 		}
 	}
@@ -166,30 +168,13 @@ pub fn externalize(
 	// Back to mutable access
 	let mut module = mbuilder.build();
 
-	// Third, rewire all calls to imported functions and update all other calls indices
+	// Third, shift all functions past the new imports and rewire calls to imported functions
+	shift_function_indices(&mut module, import_funcs_total as u32, replaces.len() as u32);
 	for section in module.sections_mut() {
-		match section {
-			&mut elements::Section::Code(ref mut code_section) => {
-				for ref mut func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), import_funcs_total, &replaces);
-				}
-			},
-			&mut elements::Section::Export(ref mut export_section) => {
-				for ref mut export in export_section.entries_mut() {
-					if let &mut elements::Internal::Function(ref mut func_index) = export.internal_mut() {
-						if *func_index >= import_funcs_total as u32 { *func_index += replaces.len() as u32; }
-					}
-				}
-			},
-			&mut elements::Section::Element(ref mut elements_section) => {
-				for ref mut segment in elements_section.entries_mut() {
-					// update all indirect call addresses initial values
-					for func_index in segment.members_mut() {
-						if *func_index >= import_funcs_total as u32 { *func_index += replaces.len() as u32; }
-					}
-				}
-			},
-			_ => { }
+		if let &mut elements::Section::Code(ref mut code_section) = section {
+			for ref mut func_body in code_section.bodies_mut() {
+				update_call_index(func_body.code_mut(), import_funcs_total, &replaces);
+			}
 		}
 	}
 
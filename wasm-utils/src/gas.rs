@@ -1,20 +1,24 @@
-use std::vec::Vec;
+use crate::std::cmp;
+use crate::std::collections::BTreeSet;
+use crate::std::fmt;
+use crate::std::ops::Range;
+use crate::std::vec::Vec;
 
 use parity_wasm::{elements, builder};
 use crate::rules;
+use crate::remap::shift_function_indices;
+use crate::stack_height;
 
-pub fn update_call_index(instructions: &mut elements::Instructions, inserted_index: u32) {
-	use parity_wasm::elements::Instruction::*;
-	for instruction in instructions.elements_mut().iter_mut() {
-		if let &mut Call(ref mut call_index) = instruction {
-			if *call_index >= inserted_index { *call_index += 1}
-		}
-	}
-}
+/// Name of the exported function returning the gas left, see `rules::GasCounter::Global`.
+pub const GAS_LEFT_EXPORT: &str = "gas_left";
+
+/// Name of the exported function seeding the gas left, see `rules::GasCounter::Global`.
+pub const SET_GAS_LEFT_EXPORT: &str = "set_gas_left";
 
 /// A block of code represented by it's start position and cost.
 ///
-/// The block typically starts with instructions such as `loop`, `block`, `if`, etc.
+/// The block typically starts with instructions such as `loop`, `if`, etc.
+/// Plain `block`s are always entered, so they are merged into the enclosing block.
 ///
 /// An example of block:
 ///
@@ -30,27 +34,79 @@ pub fn update_call_index(instructions: &mut elements::Instructions, inserted_ind
 ///
 /// The start of the block is `i64.const 1`.
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct BlockEntry {
 	/// Index of the first instruction (aka `Opcode`) in the block.
 	start_pos: usize,
+	/// Index after the last instruction in the block, including its closing `else` or `end`.
+	end_pos: usize,
 	/// Sum of costs of all instructions until end of the block.
 	cost: u32,
 }
 
+/// Adds the `cost` of the instruction at `cursor` to `blocks[index]`, applying
+/// the maximum block cost, see `rules::Set::with_max_block_cost`.
+///
+/// Returns the index of the block which the following instructions are accounted to,
+/// which is a new one starting at `cursor` if the block was split.
+fn add_cost(
+	blocks: &mut Vec<BlockEntry>,
+	index: usize,
+	cursor: usize,
+	cost: u32,
+	max_block_cost: Option<(u32, rules::OversizedBlock)>,
+) -> Result<usize, ErrorKind> {
+	let total = blocks[index].cost.checked_add(cost);
+	let policy = match max_block_cost {
+		Some((max, policy)) if total.map(|total| total > max).unwrap_or(true) => policy,
+		_ => {
+			blocks[index].cost = total.ok_or(ErrorKind::CostOverflow)?;
+			return Ok(index);
+		},
+	};
+
+	match policy {
+		rules::OversizedBlock::Reject => Err(ErrorKind::BlockCostLimit),
+		// Nothing was accounted since the start of the block, so there is nothing to
+		// split off. The cost is charged in several parts instead, see `insert_charges`.
+		rules::OversizedBlock::Split if blocks[index].start_pos == cursor => {
+			blocks[index].cost = total.ok_or(ErrorKind::CostOverflow)?;
+			Ok(index)
+		},
+		rules::OversizedBlock::Split => {
+			blocks[index].end_pos = cursor;
+			blocks.push(BlockEntry { start_pos: cursor, end_pos: cursor, cost: cost });
+			Ok(blocks.len() - 1)
+		},
+	}
+}
+
 struct Counter {
 	/// All blocks in the order of theirs start position.
 	blocks: Vec<BlockEntry>,
 
 	// Stack of blocks. Each element is an index to a `self.blocks` vector.
 	stack: Vec<usize>,
+
+	/// Blocks which were split, so that the stack entries still referring to them
+	/// continue in a new part, see `finalize`.
+	split: BTreeSet<usize>,
+
+	/// Blocks containing a branch or an exit, after which the following code
+	/// may not be executed.
+	exits: BTreeSet<usize>,
+
+	max_block_cost: Option<(u32, rules::OversizedBlock)>,
 }
 
 impl Counter {
-	fn new() -> Counter {
+	fn new(rules: &rules::Set) -> Counter {
 		Counter {
 			stack: Vec::new(),
 			blocks: Vec::new(),
+			split: BTreeSet::new(),
+			exits: BTreeSet::new(),
+			max_block_cost: rules.max_block_cost(),
 		}
 	}
 
@@ -59,151 +115,832 @@ impl Counter {
 		let block_idx = self.blocks.len();
 		self.blocks.push(BlockEntry {
 			start_pos: cursor,
+			end_pos: cursor,
 			cost: 1,
 		});
 		self.stack.push(block_idx);
 	}
 
+	/// Enter a block whose entry is unconditional, such as `block`.
+	///
+	/// Its cost is merged into the current block, so it is charged once at
+	/// the start of the current block.
+	fn merge(&mut self, cursor: usize) -> Result<(), ErrorKind> {
+		let stack_top = *self.stack.last().ok_or(ErrorKind::UnbalancedBlocks)?;
+		self.stack.push(stack_top);
+
+		// Account for the merged block the same way as `begin` does.
+		self.increment(cursor, 1)
+	}
+
 	/// Finalize the current block.
 	///
 	/// Finalized blocks have final cost which will not change later.
-	fn finalize(&mut self) -> Result<(), ()> {
-		self.stack.pop().ok_or_else(|| ())?;
+	fn finalize(&mut self, cursor: usize) -> Result<(), ErrorKind> {
+		let block_idx = self.stack.pop().ok_or(ErrorKind::UnbalancedBlocks)?;
+
+		// The end of a merged block is overwritten by the end of the block it is merged into.
+		self.blocks[block_idx].end_pos = cursor + 1;
+
+		// A branch out of the finalized block may skip the rest of the enclosing one.
+		if let Some(&top) = self.stack.last() {
+			if top != block_idx && self.exits.contains(&block_idx) {
+				self.exits.insert(top);
+			}
+		}
+
+		// The enclosing block was split inside the merged block. Branches out of the
+		// merged block skip the charge of the part after the split, so the enclosing
+		// block continues in a new part after the `end`.
+		if let Some(top) = self.stack.last_mut() {
+			if *top != block_idx && self.split.contains(top) {
+				self.blocks.push(BlockEntry {
+					start_pos: cursor + 1,
+					end_pos: cursor + 1,
+					cost: 0,
+				});
+				*top = self.blocks.len() - 1;
+			}
+		}
+		Ok(())
+	}
+
+	/// Mark the current block as containing a branch or an exit.
+	fn exit(&mut self) -> Result<(), ErrorKind> {
+		let stack_top = *self.stack.last().ok_or(ErrorKind::UnbalancedBlocks)?;
+		self.exits.insert(stack_top);
 		Ok(())
 	}
 
-	/// Increment the cost of the current block by the specified value.
-	fn increment(&mut self, val: u32) -> Result<(), ()> {
-		let stack_top = self.stack.last_mut().ok_or_else(|| ())?;
-		let top_block = self.blocks.get_mut(*stack_top).ok_or_else(|| ())?;
+	/// Whether the code following in the current block may not be executed,
+	/// because of a previous branch or exit.
+	fn may_exit(&self) -> bool {
+		self.stack.last().map(|top| self.exits.contains(top)).unwrap_or(false)
+	}
+
+	/// Increment the cost of the current block by the cost of the instruction at `cursor`.
+	fn increment(&mut self, cursor: usize, val: u32) -> Result<(), ErrorKind> {
+		let stack_top = *self.stack.last().ok_or(ErrorKind::UnbalancedBlocks)?;
+		if stack_top >= self.blocks.len() {
+			return Err(ErrorKind::UnbalancedBlocks);
+		}
 
-		top_block.cost = top_block.cost.checked_add(val).ok_or_else(|| ())?;
+		let block_idx = add_cost(&mut self.blocks, stack_top, cursor, val, self.max_block_cost)?;
+		if block_idx != stack_top {
+			// The block was split, the rest of the innermost block is accounted to
+			// the new part. The blocks it is merged into continue after its `end`.
+			self.split.insert(stack_top);
+			if let Some(top) = self.stack.last_mut() {
+				*top = block_idx;
+			}
+		}
 
 		Ok(())
 	}
 }
 
-fn inject_grow_counter(instructions: &mut elements::Instructions, grow_counter_func: u32) -> usize {
+/// Reason for an instruction to fail the gas injection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+	/// The instruction is forbidden by the gas rules.
+	Forbidden,
+	/// The cost of the block exceeds `u32::MAX`.
+	CostOverflow,
+	/// The instruction is outside of any block, e.g. an `end` without a matching block.
+	UnbalancedBlocks,
+	/// The dynamic cost of the instruction can't be charged, see `rules::DynamicCost`.
+	UnsupportedDynamicCost,
+	/// The cost of the block exceeds the maximum, see `rules::Set::with_max_block_cost`.
+	BlockCostLimit,
+}
+
+/// Error of the gas injection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+	/// An instruction could not be metered.
+	Instruction {
+		/// Index of the function body, i.e. excluding imported functions.
+		function: u32,
+		/// Index of the instruction in the function body.
+		offset: u32,
+		instruction: elements::Instruction,
+		kind: ErrorKind,
+	},
+	/// The module is already metered and `rules.already_metered()` doesn't
+	/// allow to pass it through or meter it again.
+	AlreadyMetered,
+	/// The module imports the gas function with a signature other than the
+	/// configured one, see `rules::GasImport`.
+	GasImportSignature,
+}
+
+impl fmt::Display for ErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ErrorKind::Forbidden => write!(f, "forbidden instruction"),
+			ErrorKind::CostOverflow => write!(f, "block cost overflow"),
+			ErrorKind::UnbalancedBlocks => write!(f, "unbalanced blocks"),
+			ErrorKind::UnsupportedDynamicCost => write!(f, "unsupported dynamic cost"),
+			ErrorKind::BlockCostLimit => write!(f, "block cost limit exceeded"),
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::Instruction { function, offset, ref instruction, kind } =>
+				write!(f, "{} `{}` at offset {} of function {}", kind, instruction, offset, function),
+			Error::AlreadyMetered => write!(f, "module is already metered"),
+			Error::GasImportSignature => write!(f, "gas function is imported with a different signature"),
+		}
+	}
+}
+
+/// A block charged by the injected gas counter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeteredBlock {
+	/// Instructions of the block in the original function body, including its
+	/// closing `else` or `end`.
+	///
+	/// The ranges of nested blocks are contained in the range of their parent,
+	/// but charged separately. A block split because of its cost is reported as
+	/// several blocks.
+	pub range: Range<usize>,
+	/// Static cost charged when the block is entered.
+	pub cost: u32,
+}
+
+/// The blocks charged in a function body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCosts {
+	/// Index of the function body, i.e. excluding imported functions.
+	pub function: u32,
+	/// Blocks in the order of their start.
+	pub blocks: Vec<MeteredBlock>,
+}
+
+/// Where the injected code charges gas.
+#[derive(Debug, Clone, Copy)]
+enum Meter {
+	/// Call the imported gas function with the given index and parameter type.
+	Import(u32, elements::ValueType),
+	/// Subtract from the mutable global with the given index.
+	Global(u32),
+}
+
+impl Meter {
+	/// Instructions charging a constant cost.
+	fn charge(&self, cost: u32) -> Vec<elements::Instruction> {
+		use parity_wasm::elements::Instruction::*;
+
+		match *self {
+			Meter::Import(gas_func, elements::ValueType::I32) => vec![I32Const(cost as i32), Call(gas_func)],
+			Meter::Import(gas_func, _) => vec![I64Const(cost as i64), Call(gas_func)],
+			Meter::Global(gas_global) => vec![
+				GetGlobal(gas_global),
+				I64Const(cost as i64),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+					Unreachable,
+				End,
+				GetGlobal(gas_global),
+				I64Const(cost as i64),
+				I64Sub,
+				SetGlobal(gas_global),
+			],
+		}
+	}
+
+	/// Instructions charging the `i64` cost on top of the stack.
+	///
+	/// `scratch` is an `i64` local which can be used, see `scratch_locals`.
+	fn charge_dynamic(&self, scratch: u32) -> Vec<elements::Instruction> {
+		use parity_wasm::elements::Instruction::*;
+
+		match *self {
+			// Costs which don't fit into the parameter trap instead of being truncated.
+			Meter::Import(gas_func, elements::ValueType::I32) => vec![
+				TeeLocal(scratch),
+				I64Const(u32::max_value() as i64),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+					Unreachable,
+				End,
+				GetLocal(scratch),
+				I32WrapI64,
+				Call(gas_func),
+			],
+			Meter::Import(gas_func, _) => vec![Call(gas_func)],
+			Meter::Global(gas_global) => vec![
+				SetLocal(scratch),
+				GetGlobal(gas_global),
+				GetLocal(scratch),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+					Unreachable,
+				End,
+				GetGlobal(gas_global),
+				GetLocal(scratch),
+				I64Sub,
+				SetGlobal(gas_global),
+			],
+		}
+	}
+
+	/// Locals needed by `charge_dynamic`.
+	fn scratch_locals(&self) -> Vec<elements::Local> {
+		match *self {
+			Meter::Import(_, elements::ValueType::I64) => Vec::new(),
+			Meter::Import(_, _) | Meter::Global(_) => vec![elements::Local::new(1, elements::ValueType::I64)],
+		}
+	}
+}
+
+/// Types of the operands and the result of the instructions which can have a
+/// dynamic cost, and the index of the operand giving a size to charge.
+///
+/// Only sizes can be charged, as the address or the value operands of loads and
+/// stores don't tell how much work the instruction does.
+fn dynamic_operands(instruction: &elements::Instruction)
+	-> Option<(Vec<elements::ValueType>, Option<elements::ValueType>, u32)>
+{
 	use parity_wasm::elements::Instruction::*;
-	let mut counter = 0;
-	for instruction in instructions.elements_mut() {
-		if let GrowMemory(_) = *instruction {
-			*instruction = Call(grow_counter_func);
-			counter += 1;
+	use parity_wasm::elements::ValueType::*;
+
+	match *instruction {
+		// The number of pages to grow.
+		GrowMemory(_) => Some((vec![I32], Some(I32), 0)),
+		_ => None,
+	}
+}
+
+/// Replaces the instructions with a dynamic cost by calls to their helpers.
+///
+/// The helpers are numbered from `first_helper` in the order of `helpers`,
+/// which is extended with the instructions not seen before.
+fn inject_dynamic_calls(
+	instructions: &mut elements::Instructions,
+	rules: &rules::Set,
+	first_helper: u32,
+	helpers: &mut Vec<elements::Instruction>,
+) -> Result<(), (usize, ErrorKind)> {
+	for (cursor, instruction) in instructions.elements_mut().iter_mut().enumerate() {
+		let operand = match rules.dynamic_cost(instruction) {
+			Some(dynamic) => dynamic.operand,
+			None if is_grow_curve(rules, instruction) => 0,
+			None => continue,
+		};
+
+		let size = dynamic_operands(instruction).map(|(_, _, size)| size);
+		if size != Some(operand) {
+			return Err((cursor, ErrorKind::UnsupportedDynamicCost));
 		}
+
+		let helper = match helpers.iter().position(|helper| helper == instruction) {
+			Some(helper) => helper,
+			None => {
+				helpers.push(instruction.clone());
+				helpers.len() - 1
+			},
+		};
+		*instruction = elements::Instruction::Call(first_helper + helper as u32);
 	}
-	counter
+
+	Ok(())
 }
 
-fn add_grow_counter(module: elements::Module, rules: &rules::Set, gas_func: u32) -> elements::Module {
+/// Adds a helper function for every instruction in `helpers`, which charges its
+/// dynamic cost and executes it.
+fn add_dynamic_helpers(
+	module: elements::Module,
+	rules: &rules::Set,
+	meter: Meter,
+	helpers: Vec<elements::Instruction>,
+) -> elements::Module {
 	use parity_wasm::elements::Instruction::*;
 
 	let mut b = builder::from_module(module);
-	b.push_function(
-		builder::function()
-			.signature().params().i64().build().with_return_type(Some(elements::ValueType::I64)).build()
-			.body()
-				.with_instructions(elements::Instructions::new(vec![
-					GetLocal(0),
-					GetLocal(0),
-					I64Const(rules.grow_cost() as i64),
+	for instruction in helpers {
+		let (params, result, _) = dynamic_operands(&instruction)
+			.expect("Only instructions with known operand types are replaced");
+
+		let (locals, body) = match rules.grow_curve() {
+			Some(curve) if is_grow_curve(rules, &instruction) => grow_curve_helper(curve, meter, instruction),
+			_ => {
+				let dynamic = rules.dynamic_cost(&instruction)
+					.expect("Only instructions with a dynamic cost are replaced");
+				let scratch = params.len() as u32;
+
+				let mut body = vec![
+					GetLocal(dynamic.operand),
+					I64ExtendUI32,
+					I64Const(dynamic.cost as i64),
 					I64Mul,
-					// todo: there should be strong guarantee that it does not return anything on stack?
-					Call(gas_func),
-					GrowMemory(0),
-					End,
-				]))
+				];
+				body.extend(meter.charge_dynamic(scratch));
+				body.extend((0..scratch).map(GetLocal));
+				body.push(instruction);
+				body.push(End);
+				(meter.scratch_locals(), body)
+			},
+		};
+
+		b.push_function(
+			builder::function()
+				.signature().with_params(params).with_return_type(result).build()
+				.body()
+					.with_locals(locals)
+					.with_instructions(elements::Instructions::new(body))
+					.build()
 				.build()
-			.build()
-	);
+		);
+	}
 
 	b.build()
 }
 
-pub fn inject_counter(
-	instructions: &mut elements::Instructions,
-	rules: &rules::Set,
-	gas_func: u32,
-) -> Result<(), ()> {
+/// Whether `instruction` grows the memory, priced by a curve.
+fn is_grow_curve(rules: &rules::Set, instruction: &elements::Instruction) -> bool {
+	rules.grow_curve().is_some() && rules::InstructionType::op(instruction) == rules::InstructionType::GrowMemory
+}
+
+/// Locals and body of the helper replacing `grow_instruction`, which charges
+/// the price of the new size of the memory on `curve` minus the price of its
+/// current size.
+fn grow_curve_helper(curve: &rules::GrowCost, meter: Meter, grow_instruction: elements::Instruction)
+	-> (Vec<elements::Local>, Vec<elements::Instruction>)
+{
+	use parity_wasm::elements::Instruction::*;
+
+	// The parameter is the number of pages to grow, followed by the `i64`
+	// locals of the current and the new size.
+	let (current, new, scratch) = (1, 2, 3);
+
+	let mut body = vec![
+		CurrentMemory(0),
+		I64ExtendUI32,
+		SetLocal(current),
+		GetLocal(0),
+		I64ExtendUI32,
+		GetLocal(current),
+		I64Add,
+		TeeLocal(new),
+		// Growing beyond the 4GiB of a 32 bit memory always fails, so it is not
+		// charged. This also keeps the prices from overflowing.
+		I64Const(MAX_MEMORY_PAGES),
+		I64GtU,
+		If(elements::BlockType::NoResult),
+			I32Const(-1),
+			Return,
+		End,
+	];
+	body.extend(memory_price(curve, new));
+	body.extend(memory_price(curve, current));
+	body.push(I64Sub);
+	body.extend(meter.charge_dynamic(scratch));
+	body.push(GetLocal(0));
+	body.push(grow_instruction);
+	body.push(End);
+
+	let mut locals = vec![elements::Local::new(2, elements::ValueType::I64)];
+	locals.extend(meter.scratch_locals());
+	(locals, body)
+}
+
+/// Maximum number of pages of a 32 bit memory.
+const MAX_MEMORY_PAGES: i64 = 65536;
+
+/// Instructions computing the `i64` price on `curve` of a memory with as many
+/// pages as the `i64` local `pages`, which is at most `MAX_MEMORY_PAGES`.
+fn memory_price(curve: &rules::GrowCost, pages: u32) -> Vec<elements::Instruction> {
 	use parity_wasm::elements::Instruction::*;
 
-	let mut counter = Counter::new();
+	match *curve {
+		rules::GrowCost::Linear(cost) => vec![GetLocal(pages), I64Const(cost as i64), I64Mul],
+		rules::GrowCost::Quadratic { linear, quadratic_divisor } => vec![
+			GetLocal(pages),
+			I64Const(linear as i64),
+			I64Mul,
+			GetLocal(pages),
+			GetLocal(pages),
+			I64Mul,
+			I64Const(cmp::max(quadratic_divisor, 1) as i64),
+			I64DivU,
+			I64Add,
+		],
+		rules::GrowCost::Steps(ref steps) => {
+			let mut code = vec![I64Const(0)];
+			let mut previous_cost = 0;
+			for &(first_page, cost) in steps {
+				// Every page from `first_page` on costs the difference to the
+				// previous step more, which may be negative.
+				code.extend(vec![
+					GetLocal(pages),
+					I64Const(first_page as i64),
+					I64Sub,
+					I64Const(0),
+					GetLocal(pages),
+					I64Const(first_page as i64),
+					I64GtU,
+					Select,
+					I64Const(cost as i64 - previous_cost),
+					I64Mul,
+					I64Add,
+				]);
+				previous_cost = cost as i64;
+			}
+			code
+		},
+	}
+}
+
+/// Computes the blocks of a function body and their costs.
+///
+/// On failure, returns the offset of the offending instruction.
+fn count_blocks(instructions: &elements::Instructions, rules: &rules::Set)
+	-> Result<Vec<BlockEntry>, (usize, ErrorKind)>
+{
+	let mut counter = Counter::new(rules);
 
 	// Begin an implicit function (i.e. `func...end`) block.
 	counter.begin(0);
 
-	for cursor in 0..instructions.elements().len() {
-		let instruction = &instructions.elements()[cursor];
-		match *instruction {
-			Block(_) | If(_) | Loop(_) => {
-				// Increment previous block with the cost of the current opcode.
-				let instruction_cost = rules.process(instruction)?;
-				counter.increment(instruction_cost)?;
+	for (cursor, instruction) in instructions.elements().iter().enumerate() {
+		count_instruction(&mut counter, rules, cursor, instruction).map_err(|kind| (cursor, kind))?;
+	}
+
+	Ok(counter.blocks)
+}
+
+/// Accounts for the instruction at `cursor`.
+fn count_instruction(
+	counter: &mut Counter,
+	rules: &rules::Set,
+	cursor: usize,
+	instruction: &elements::Instruction,
+) -> Result<(), ErrorKind> {
+	use parity_wasm::elements::Instruction::*;
+
+	match *instruction {
+		Block(_) => {
+			let instruction_cost = rules.process(instruction).map_err(|_| ErrorKind::Forbidden)?;
+			counter.increment(cursor, instruction_cost)?;
 
-				// Begin new block. The cost of the following opcodes until `End` or `Else` will
-				// be included into this block.
+			// Unless the current block can be left before, the block is always
+			// entered, so its cost can be charged together with the current block.
+			if counter.may_exit() {
 				counter.begin(cursor + 1);
+			} else {
+				counter.merge(cursor)?;
 			}
-			End => {
-				// Just finalize current block.
-				counter.finalize()?;
-			},
-			Else => {
-				// `Else` opcode is being encountered. So the case we are looking at:
-				//
-				// if
-				//   ...
-				// else <-- cursor
-				//   ...
-				// end
-				//
-				// Finalize the current block ('then' part of the if statement),
-				// and begin another one for the 'else' part.
-				counter.finalize()?;
-				counter.begin(cursor + 1);
+		}
+		If(_) | Loop(_) => {
+			// Increment previous block with the cost of the current opcode.
+			let instruction_cost = rules.process(instruction).map_err(|_| ErrorKind::Forbidden)?;
+			counter.increment(cursor, instruction_cost)?;
+
+			// Begin new block. The cost of the following opcodes until `End` or `Else` will
+			// be included into this block.
+			counter.begin(cursor + 1);
+
+			// The start of a loop is charged again on every iteration.
+			if let Loop(_) = *instruction {
+				counter.increment(cursor + 1, rules.loop_iteration_cost())?;
 			}
-			_ => {
-				// An ordinal non control flow instruction. Just increment the cost of the current block.
-				let instruction_cost = rules.process(instruction)?;
-				counter.increment(instruction_cost)?;
+		}
+		End => {
+			// Just finalize current block.
+			counter.finalize(cursor)?;
+		},
+		Else => {
+			// `Else` opcode is being encountered. So the case we are looking at:
+			//
+			// if
+			//   ...
+			// else <-- cursor
+			//   ...
+			// end
+			//
+			// Finalize the current block ('then' part of the if statement),
+			// and begin another one for the 'else' part.
+			counter.finalize(cursor)?;
+			counter.begin(cursor + 1);
+		}
+		Br(_) | BrIf(_) | BrTable(_, _) | Return | Unreachable => {
+			let instruction_cost = rules.process(instruction).map_err(|_| ErrorKind::Forbidden)?;
+			counter.increment(cursor, instruction_cost)?;
+
+			// The following blocks may be skipped, so they can't be merged.
+			counter.exit()?;
+		}
+		_ => {
+			// An ordinal non control flow instruction. Just increment the cost of the current block.
+			let instruction_cost = rules.process(instruction).map_err(|_| ErrorKind::Forbidden)?;
+			counter.increment(cursor, instruction_cost)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Computes the straight-line segments of a function body and their costs,
+/// see `rules::Set::with_precise_metering`.
+///
+/// A segment ends after every control flow and branch instruction.
+fn count_segments(instructions: &elements::Instructions, rules: &rules::Set)
+	-> Result<Vec<BlockEntry>, (usize, ErrorKind)>
+{
+	use parity_wasm::elements::Instruction::*;
+
+	let max_block_cost = rules.max_block_cost();
+	let mut segments = vec![BlockEntry { start_pos: 0, end_pos: 0, cost: 0 }];
+
+	for (cursor, instruction) in instructions.elements().iter().enumerate() {
+		// Every block charged by `count_blocks` costs 1 for the `End` or `Else`
+		// closing it, so the segments closed by them cost the same.
+		let instruction_cost = match *instruction {
+			End | Else => 1,
+			_ => rules.process(instruction).map_err(|_| (cursor, ErrorKind::Forbidden))?,
+		};
+		let segment = segments.len() - 1;
+		let segment = add_cost(&mut segments, segment, cursor, instruction_cost, max_block_cost)
+			.map_err(|kind| (cursor, kind))?;
+		segments[segment].end_pos = cursor + 1;
+
+		match *instruction {
+			Block(_) | If(_) | Loop(_) | Else | End | Br(_) | BrIf(_) | BrTable(_, _) | Return => {
+				segments.push(BlockEntry { start_pos: cursor + 1, end_pos: cursor + 1, cost: 0 });
 			}
+			_ => { }
+		}
+
+		// The start of a loop is charged again on every iteration.
+		if let Loop(_) = *instruction {
+			let segment = segments.len() - 1;
+			add_cost(&mut segments, segment, cursor + 1, rules.loop_iteration_cost(), max_block_cost)
+				.map_err(|kind| (cursor, kind))?;
 		}
 	}
 
-	// Then insert metering calls.
+	Ok(segments)
+}
+
+/// Computes the blocks to charge according to `rules`.
+fn metered_blocks(instructions: &elements::Instructions, rules: &rules::Set)
+	-> Result<Vec<BlockEntry>, (usize, ErrorKind)>
+{
+	if rules.precise() {
+		count_segments(instructions, rules)
+	} else {
+		count_blocks(instructions, rules)
+	}
+}
+
+/// Inserts the charge of every block at its start.
+///
+/// Costs above `max_charge` are charged in several parts.
+fn insert_charges(instructions: &mut elements::Instructions, blocks: &[BlockEntry], meter: Meter, max_charge: u32) {
 	let mut cumulative_offset = 0;
-	for block in counter.blocks {
+	for block in blocks {
+		// Segments without cost, e.g. the one following the final `end`, need no charge.
+		if block.cost == 0 { continue; }
+
 		let effective_pos = block.start_pos + cumulative_offset;
+		let mut charge = Vec::new();
+		let mut cost = block.cost;
+		while cost > max_charge {
+			charge.extend(meter.charge(max_charge));
+			cost -= max_charge;
+		}
+		charge.extend(meter.charge(cost));
 
-		instructions.elements_mut().insert(effective_pos, I64Const(block.cost as i64));
-		instructions.elements_mut().insert(effective_pos+1, Call(gas_func));
+		// Take into account the inserted instructions.
+		cumulative_offset += charge.len();
+		instructions.elements_mut().splice(effective_pos..effective_pos, charge);
+	}
+}
+
+/// Injects the counter into every function body, and replaces the instructions
+/// with a dynamic cost by calls to helpers.
+///
+/// Returns the instructions for which helpers have to be added, and the
+/// charged blocks of every function body.
+fn inject_counters(module: &mut elements::Module, rules: &rules::Set, meter: Meter)
+	-> Result<(Vec<elements::Instruction>, Vec<FunctionCosts>), Error>
+{
+	let first_helper = module.functions_space() as u32;
+	let max_charge = match rules.max_block_cost() {
+		Some((max, rules::OversizedBlock::Split)) => max,
+		_ => u32::max_value(),
+	};
+	let mut helpers = Vec::new();
+	let mut costs = Vec::new();
 
-		// Take into account these two inserted instructions.
-		cumulative_offset += 2;
+	for section in module.sections_mut() {
+		if let &mut elements::Section::Code(ref mut code_section) = section {
+			for (index, func_body) in code_section.bodies_mut().iter_mut().enumerate() {
+				// The dynamic calls replace instructions one by one, so the offsets in
+				// the errors and the blocks refer to the original function body.
+				let result = metered_blocks(func_body.code(), rules).and_then(|blocks| {
+					inject_dynamic_calls(func_body.code_mut(), rules, first_helper, &mut helpers)?;
+					Ok(blocks)
+				});
+				let blocks = result.map_err(|(offset, kind)| Error::Instruction {
+					function: index as u32,
+					offset: offset as u32,
+					instruction: func_body.code().elements()[offset].clone(),
+					kind: kind,
+				})?;
+				insert_charges(func_body.code_mut(), &blocks, meter, max_charge);
+
+				costs.push(FunctionCosts {
+					function: index as u32,
+					blocks: blocks.into_iter()
+						.filter(|block| block.cost > 0)
+						.map(|block| MeteredBlock { range: block.start_pos..block.end_pos, cost: block.cost })
+						.collect(),
+				});
+			}
+		}
 	}
 
-	Ok(())
+	Ok((helpers, costs))
 }
 
-/// Injects gas counter.
-///
-/// Can only fail if encounters operation forbidden by gas rules,
-/// in this case it returns error with the original module.
-pub fn inject_gas_counter(module: elements::Module, rules: &rules::Set)
-	-> Result<elements::Module, elements::Module>
+/// Returns the function index and the type of the imported gas function,
+/// if the module imports it.
+fn gas_import<'a>(module: &'a elements::Module, import: &rules::GasImport)
+	-> Option<(u32, Option<&'a elements::FunctionType>)>
 {
+	let entries = module.import_section().map(|section| section.entries()).unwrap_or(&[]);
+	let mut func_index = 0;
+	for entry in entries {
+		if let elements::External::Function(type_ref) = *entry.external() {
+			if entry.module() == import.module && entry.field() == import.field {
+				let func_type = module.type_section()
+					.and_then(|section| section.types().get(type_ref as usize))
+					.map(|ty| match *ty { elements::Type::Function(ref func_type) => func_type });
+				return Some((func_index, func_type));
+			}
+			func_index += 1;
+		}
+	}
+	None
+}
+
+/// Returns true if `func_type` is the signature of the gas function.
+fn is_gas_signature(func_type: Option<&elements::FunctionType>, import: &rules::GasImport) -> bool {
+	func_type
+		.map(|func_type| func_type.params() == &[import.param][..] && func_type.return_type().is_none())
+		.unwrap_or(false)
+}
+
+/// Returns true if the instructions are a charge injected with the gas function.
+fn is_charge(instructions: &[elements::Instruction], gas_func: u32) -> bool {
+	use parity_wasm::elements::Instruction::*;
+
+	match (instructions.get(0), instructions.get(1)) {
+		(Some(&I64Const(_)), Some(&Call(func))) | (Some(&I32Const(_)), Some(&Call(func))) => func == gas_func,
+		_ => false,
+	}
+}
+
+/// Returns true if the module exports the helpers of the gas global.
+fn has_gas_global(module: &elements::Module) -> bool {
+	module.export_section()
+		.map(|section| section.entries().iter().any(|entry| entry.field() == GAS_LEFT_EXPORT))
+		.unwrap_or(false)
+}
+
+/// Returns the instruction executed by `body`, if it is a helper added by
+/// `add_dynamic_helpers` which charges with the gas function.
+fn helper_instruction(body: &elements::FuncBody, gas_func: u32) -> Option<&elements::Instruction> {
+	use parity_wasm::elements::Instruction::*;
+
+	let code = body.code().elements();
+	if code.len() < 2 || code[code.len() - 1] != End {
+		return None;
+	}
+	let starts_as_helper = match (&code[0], &code[1]) {
+		(&GetLocal(_), &I64ExtendUI32) | (&CurrentMemory(_), &I64ExtendUI32) => true,
+		_ => false,
+	};
+	let calls_gas_only = code.iter().any(|instruction| *instruction == Call(gas_func)) &&
+		code.iter().all(|instruction| match *instruction {
+			Call(func) => func == gas_func,
+			CallIndirect(_, _) => false,
+			_ => true,
+		});
+
+	let instruction = &code[code.len() - 2];
+	if starts_as_helper && calls_gas_only && dynamic_operands(instruction).is_some() {
+		Some(instruction)
+	} else {
+		None
+	}
+}
+
+/// Number of helpers added by `add_dynamic_helpers` at the end of `bodies`.
+fn helpers_count(bodies: &[elements::FuncBody], gas_func: u32) -> usize {
+	bodies.iter().rev().take_while(|body| helper_instruction(body, gas_func).is_some()).count()
+}
+
+/// Returns true if the module is already metered according to `rules`.
+///
+/// With the imported gas function, a module is metered if it imports the gas
+/// function and every function body starts with a charge, except the helpers
+/// charging dynamic costs and the thunks added by `stack_height::inject_limiter`
+/// afterwards. With the gas global, it is metered if it exports the helpers of
+/// the global.
+pub fn is_metered(module: &elements::Module, rules: &rules::Set) -> bool {
+	match rules.gas_counter() {
+		rules::GasCounter::Global => has_gas_global(module),
+		rules::GasCounter::Import => {
+			let gas_func = match gas_import(module, rules.gas_import()) {
+				Some((gas_func, _)) => gas_func,
+				None => return false,
+			};
+			let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+			let thunks = bodies.iter().rev().take_while(|body| stack_height::is_thunk(body)).count();
+			let bodies = &bodies[..bodies.len() - thunks];
+			let functions = &bodies[..bodies.len() - helpers_count(bodies, gas_func)];
+			!functions.is_empty() && functions.iter().all(|body| is_charge(body.code().elements(), gas_func))
+		},
+	}
+}
+
+/// Removes the gas counters injected by `inject_gas_counter`.
+///
+/// Every charge is removed, including the ones `OversizedBlock::Split` puts in the
+/// middle of a block.
+fn strip_counter(instructions: &mut elements::Instructions, gas_func: u32) {
+	let elements = instructions.elements_mut();
+	let mut cursor = 0;
+	while cursor + 1 < elements.len() {
+		if is_charge(&elements[cursor..], gas_func) {
+			elements.drain(cursor..cursor + 2);
+		} else {
+			cursor += 1;
+		}
+	}
+}
+
+/// Removes the helpers added by `add_dynamic_helpers`, and replaces the calls to
+/// them with the instructions they execute.
+fn strip_helpers(module: &mut elements::Module, gas_func: u32) {
+	use parity_wasm::elements::Instruction::*;
+
+	let helpers: Vec<elements::Instruction> = {
+		let bodies = module.code_section().map(|section| section.bodies()).unwrap_or(&[]);
+		bodies[bodies.len() - helpers_count(bodies, gas_func)..].iter()
+			.filter_map(|body| helper_instruction(body, gas_func).cloned())
+			.collect()
+	};
+	if helpers.is_empty() {
+		return;
+	}
+	// The helpers are the last functions.
+	let first_helper = (module.functions_space() - helpers.len()) as u32;
+
+	for section in module.sections_mut() {
+		match *section {
+			elements::Section::Code(ref mut code_section) => {
+				let functions = code_section.bodies().len() - helpers.len();
+				code_section.bodies_mut().truncate(functions);
+				for func_body in code_section.bodies_mut() {
+					for instruction in func_body.code_mut().elements_mut().iter_mut() {
+						let helper = match *instruction {
+							Call(func) if func >= first_helper => &helpers[(func - first_helper) as usize],
+							_ => continue,
+						};
+						*instruction = helper.clone();
+					}
+				}
+			},
+			elements::Section::Function(ref mut function_section) => {
+				let functions = function_section.entries().len() - helpers.len();
+				function_section.entries_mut().truncate(functions);
+			},
+			_ => { }
+		}
+	}
+}
+
+/// Adds the gas function import, returning the module and the index of the gas function.
+fn add_gas_import(module: elements::Module, import: &rules::GasImport) -> (elements::Module, u32) {
 	// Injecting gas counting external
 	let mut mbuilder = builder::from_module(module);
 	let import_sig = mbuilder.push_signature(
 		builder::signature()
-			.param().i64()
+			.with_param(import.param)
 			.build_sig()
 		);
 
 	mbuilder.push_import(
 		builder::import()
-			.module("ethereum")
-			.field("useGas")
+			.module(&import.module)
+			.field(&import.field)
 			.external().func(import_sig)
 			.build()
 		);
@@ -211,66 +948,1207 @@ pub fn inject_gas_counter(module: elements::Module, rules: &rules::Set)
 	// back to plain module
 	let mut module = mbuilder.build();
 
-	// calculate actual function index of the imported definition
-	//    (substract all imports that are NOT functions)
+	// calculate actual function index of the imported definition
+	//    (substract all imports that are NOT functions)
+
+	let gas_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
+
+	// All functions from `gas_func` on moved by one
+	shift_function_indices(&mut module, gas_func, 1);
+
+	(module, gas_func)
+}
+
+/// Adds the mutable gas global and the exported functions to seed and read it.
+///
+/// Returns the module and the index of the gas global.
+fn add_gas_global(module: elements::Module) -> (elements::Module, u32) {
+	use parity_wasm::elements::Instruction::*;
+
+	let gas_global = module.globals_space() as u32;
+	let first_func = module.functions_space() as u32;
+
+	let mut b = builder::from_module(module)
+		.with_global(elements::GlobalEntry::new(
+			elements::GlobalType::new(elements::ValueType::I64, true),
+			elements::InitExpr::new(vec![I64Const(0), End]),
+		));
+
+	b.push_function(
+		builder::function()
+			.signature().with_return_type(Some(elements::ValueType::I64)).build()
+			.body()
+				.with_instructions(elements::Instructions::new(vec![
+					GetGlobal(gas_global),
+					End,
+				]))
+				.build()
+			.build()
+	);
+	b.push_function(
+		builder::function()
+			.signature().params().i64().build().build()
+			.body()
+				.with_instructions(elements::Instructions::new(vec![
+					GetLocal(0),
+					SetGlobal(gas_global),
+					End,
+				]))
+				.build()
+			.build()
+	);
+	b.push_export(
+		builder::export()
+			.field(GAS_LEFT_EXPORT)
+			.internal().func(first_func)
+			.build()
+	);
+	b.push_export(
+		builder::export()
+			.field(SET_GAS_LEFT_EXPORT)
+			.internal().func(first_func + 1)
+			.build()
+	);
+
+	(b.build(), gas_global)
+}
+
+/// Injects a gas counter kept in a mutable global, see `rules::GasCounter::Global`.
+fn inject_gas_global(module: elements::Module, rules: &rules::Set)
+	-> Result<(elements::Module, Vec<FunctionCosts>), Error>
+{
+	if has_gas_global(&module) {
+		return match rules.already_metered() {
+			rules::AlreadyMetered::PassThrough => Ok((module, Vec::new())),
+			// Stripping is only supported for the imported gas function.
+			rules::AlreadyMetered::Reject | rules::AlreadyMetered::Remeter => Err(Error::AlreadyMetered),
+		};
+	}
+
+	let mut module = module;
+	let meter = Meter::Global(module.globals_space() as u32);
+	let (helpers, costs) = inject_counters(&mut module, rules, meter)?;
+
+	// The helpers have to be the first functions added, as the calls to them
+	// were already injected.
+	let module = add_dynamic_helpers(module, rules, meter, helpers);
+
+	Ok((add_gas_global(module).0, costs))
+}
+
+/// Injects gas counter.
+///
+/// Fails if an instruction can't be metered, e.g. because it is forbidden by
+/// the gas rules, see `Error`.
+///
+/// If the module already imports the gas function, the import is reused.
+/// Modules which are already metered are handled according to
+/// `rules.already_metered()`, see `is_metered`.
+pub fn inject_gas_counter(module: elements::Module, rules: &rules::Set)
+	-> Result<elements::Module, Error>
+{
+	inject_gas_counter_with_costs(module, rules).map(|(module, _)| module)
+}
+
+/// Injects gas counter like `inject_gas_counter`, and also returns the blocks
+/// charged in every function body.
+///
+/// The costs are static, i.e. dynamic costs charged by helper functions are not
+/// included. No blocks are returned if an already metered module is passed through.
+pub fn inject_gas_counter_with_costs(module: elements::Module, rules: &rules::Set)
+	-> Result<(elements::Module, Vec<FunctionCosts>), Error>
+{
+	if rules.gas_counter() == rules::GasCounter::Global {
+		return inject_gas_global(module, rules);
+	}
+
+	let import = rules.gas_import();
+	let existing_import = gas_import(&module, import)
+		.map(|(gas_func, func_type)| (gas_func, is_gas_signature(func_type, import)));
+	let (mut module, gas_func) = match existing_import {
+		Some((_, false)) => return Err(Error::GasImportSignature),
+		Some((gas_func, true)) if is_metered(&module, rules) => match rules.already_metered() {
+			rules::AlreadyMetered::Reject => return Err(Error::AlreadyMetered),
+			rules::AlreadyMetered::PassThrough => return Ok((module, Vec::new())),
+			rules::AlreadyMetered::Remeter => {
+				let mut module = module;
+				strip_helpers(&mut module, gas_func);
+				for section in module.sections_mut() {
+					if let &mut elements::Section::Code(ref mut code_section) = section {
+						for ref mut func_body in code_section.bodies_mut() {
+							strip_counter(func_body.code_mut(), gas_func);
+						}
+					}
+				}
+				(module, gas_func)
+			},
+		},
+		// The existing import is reused, so no indices have to be updated.
+		Some((gas_func, true)) => (module, gas_func),
+		None => add_gas_import(module, import),
+	};
+
+	let meter = Meter::Import(gas_func, import.param);
+	let (helpers, costs) = inject_counters(&mut module, rules, meter)?;
+
+	Ok((add_dynamic_helpers(module, rules, meter, helpers), costs))
+}
+
+#[cfg(test)]
+mod tests {
+
+	extern crate wabt;
+
+	use parity_wasm::{serialize, builder, elements};
+	use super::*;
+	use rules;
+
+	#[test]
+	fn simple_grow() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							GrowMemory(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default().with_grow_cost(10000)).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(3),
+				Call(0),
+				GetGlobal(0),
+				Call(2),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+		assert_eq!(
+			&vec![
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(10000),
+				I64Mul,
+				Call(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[1]
+				.code().elements()
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::wasm2wat(&binary).unwrap();
+	}
+
+	#[test]
+	fn dynamic_helper() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.memory().build()
+			.function()
+				.signature().return_type().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							I32Const(1),
+							GrowMemory(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default()
+			.with_gas_counter(rules::GasCounter::Global)
+			.with_dynamic_cost(rules::InstructionType::GrowMemory, rules::DynamicCost { operand: 0, cost: 3 });
+		let injected_module = inject_gas_counter(module, &rules).unwrap();
+
+		assert_eq!(
+			&vec![
+				I32Const(1),
+				Call(1),
+				End
+			][..],
+			&injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()[10..]
+		);
+		assert_eq!(
+			&vec![
+				GetLocal(0),
+				I64ExtendUI32,
+				I64Const(3),
+				I64Mul,
+				SetLocal(1),
+				GetGlobal(0),
+				GetLocal(1),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+					Unreachable,
+				End,
+				GetGlobal(0),
+				GetLocal(1),
+				I64Sub,
+				SetGlobal(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[1]
+				.code().elements()
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn dynamic_cost_unsupported_operand() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = || builder::module()
+			.memory().build()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							I32Const(1),
+							I32Const(2),
+							I32Store(2, 0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		// Neither the address nor the stored value are sizes.
+		for operand in 0..2 {
+			let rules = rules::Set::default()
+				.with_dynamic_cost(rules::InstructionType::Store, rules::DynamicCost { operand: operand, cost: 2 });
+
+			assert_eq!(
+				inject_gas_counter(module(), &rules),
+				Err(Error::Instruction {
+					function: 0,
+					offset: 2,
+					instruction: I32Store(2, 0),
+					kind: ErrorKind::UnsupportedDynamicCost,
+				})
+			);
+		}
+	}
+
+	#[test]
+	fn grow_curve() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = || builder::module()
+			.memory().build()
+			.function()
+				.signature().return_type().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							I32Const(1),
+							GrowMemory(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default()
+			.with_grow_curve(rules::GrowCost::Steps(vec![(16, 100), (0, 10)]));
+		assert_eq!(rules.grow_curve(), Some(&rules::GrowCost::Steps(vec![(0, 10), (16, 100)])));
+		assert_eq!(rules.grow_cost(), 0);
+		let injected_module = inject_gas_counter(module(), &rules).unwrap();
+
+		assert_eq!(
+			&vec![
+				CurrentMemory(0),
+				I64ExtendUI32,
+				SetLocal(1),
+				GetLocal(0),
+				I64ExtendUI32,
+				GetLocal(1),
+				I64Add,
+				TeeLocal(2),
+				I64Const(65536),
+				I64GtU,
+				If(elements::BlockType::NoResult),
+					I32Const(-1),
+					Return,
+				End,
+				I64Const(0),
+				GetLocal(2), I64Const(0), I64Sub, I64Const(0), GetLocal(2), I64Const(0), I64GtU, Select,
+				I64Const(10), I64Mul, I64Add,
+				GetLocal(2), I64Const(16), I64Sub, I64Const(0), GetLocal(2), I64Const(16), I64GtU, Select,
+				I64Const(90), I64Mul, I64Add,
+				I64Const(0),
+				GetLocal(1), I64Const(0), I64Sub, I64Const(0), GetLocal(1), I64Const(0), I64GtU, Select,
+				I64Const(10), I64Mul, I64Add,
+				GetLocal(1), I64Const(16), I64Sub, I64Const(0), GetLocal(1), I64Const(16), I64GtU, Select,
+				I64Const(90), I64Mul, I64Add,
+				I64Sub,
+				Call(0),
+				GetLocal(0),
+				GrowMemory(0),
+				End,
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[1]
+				.code().elements()
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+
+		// The quadratic curve, charged through the global and an `i32` import.
+		let curve = rules::GrowCost::Quadratic { linear: 3, quadratic_divisor: 512 };
+		let import = rules::GasImport { param: elements::ValueType::I32, ..Default::default() };
+		for rules in vec![
+			rules::Set::default().with_gas_counter(rules::GasCounter::Global).with_grow_curve(curve.clone()),
+			rules::Set::default().with_gas_import(import).with_grow_curve(curve),
+		] {
+			let injected_module = inject_gas_counter(module(), &rules).unwrap();
+			let binary = serialize(injected_module).expect("serialization failed");
+			self::wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+		}
+
+		// A linear curve charges per page, without reading the memory size.
+		let rules = rules::Set::default().with_grow_curve(rules::GrowCost::Linear(7));
+		assert_eq!(rules.grow_curve(), None);
+		assert_eq!(rules.grow_cost(), 7);
+		let rules = rules.with_grow_curve(rules::GrowCost::Steps(vec![(0, 1)])).with_grow_cost(0);
+		assert_eq!(rules.grow_curve(), None);
+		assert_eq!(inject_gas_counter(module(), &rules).unwrap().functions_space(), 2);
+	}
+
+	#[test]
+	fn grow_no_gas_no_track() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							GrowMemory(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &rules::Set::default()).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(3),
+				Call(0),
+				GetGlobal(0),
+				GrowMemory(0),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+
+		assert_eq!(injected_module.functions_space(), 2);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::wasm2wat(&binary).unwrap();
+	}
+
+	#[test]
+	fn simple() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(2),
+				Call(0),
+				GetGlobal(0),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn nested() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							Block(elements::BlockType::NoResult),
+								GetGlobal(0),
+								GetGlobal(0),
+								GetGlobal(0),
+							End,
+							GetGlobal(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(8),
+				Call(0),
+				GetGlobal(0),
+				Block(elements::BlockType::NoResult),
+					GetGlobal(0),
+					GetGlobal(0),
+					GetGlobal(0),
+				End,
+				GetGlobal(0),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn nested_in_loop() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Loop(elements::BlockType::NoResult),
+								Block(elements::BlockType::NoResult),
+									Block(elements::BlockType::NoResult),
+										GetGlobal(0),
+										BrIf(2),
+									End,
+									GetGlobal(0),
+									Drop,
+								End,
+							End,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(2),
+				Call(0),
+				Loop(elements::BlockType::NoResult),
+					I64Const(9),
+					Call(0),
+					Block(elements::BlockType::NoResult),
+						Block(elements::BlockType::NoResult),
+							GetGlobal(0),
+							BrIf(2),
+						End,
+						GetGlobal(0),
+						Drop,
+					End,
+				End,
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn block_after_branch() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetLocal(0),
+							BrIf(0),
+							Block(elements::BlockType::NoResult),
+								Nop, Nop, Nop, Nop, Nop,
+							End,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+
+		// The `br_if` may skip the block, so it is charged separately.
+		assert_eq!(
+			&vec![
+				I64Const(4),
+				Call(0),
+				GetLocal(0),
+				BrIf(0),
+				Block(elements::BlockType::NoResult),
+					I64Const(6),
+					Call(0),
+					Nop, Nop, Nop, Nop, Nop,
+				End,
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn dynamic_cost() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i32()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Block(elements::BlockType::NoResult),
+								GetGlobal(0),
+								BrTable(vec![0, 0, 0].into_boxed_slice(), 0),
+							End,
+							GetGlobal(0),
+							CallIndirect(0, 0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let mut entries = crate::std::collections::BTreeMap::new();
+		entries.insert(rules::InstructionType::CallIndirect, rules::Metering::Fixed(20));
+		let rules = rules::Set::new(1, entries).with_br_table_target_cost(5);
+		let injected_module = inject_gas_counter(module, &rules).unwrap();
+
+		// The `br_table` costs 1 + 4 * 5 and the `call_indirect` 20.
+		assert_eq!(
+			&vec![
+				I64Const(46),
+				Call(0),
+				Block(elements::BlockType::NoResult),
+					GetGlobal(0),
+					BrTable(vec![0, 0, 0].into_boxed_slice(), 0),
+				End,
+				GetGlobal(0),
+				CallIndirect(0, 0),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn costs() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i32()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							If(elements::BlockType::NoResult),
+								GetGlobal(0),
+							Else,
+								GetGlobal(0),
+								GetGlobal(0),
+							End,
+							GetGlobal(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let (_, costs) = inject_gas_counter_with_costs(module, &Default::default()).unwrap();
+
+		assert_eq!(
+			costs,
+			vec![FunctionCosts {
+				function: 0,
+				blocks: vec![
+					MeteredBlock { range: 0..9, cost: 4 },
+					MeteredBlock { range: 2..4, cost: 2 },
+					MeteredBlock { range: 4..7, cost: 3 },
+				],
+			}]
+		);
+	}
+
+	#[test]
+	fn max_block_cost_reject() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i32()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							GetGlobal(0),
+							GetGlobal(0),
+							Drop,
+							Drop,
+							Drop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default().with_max_block_cost(3, rules::OversizedBlock::Reject);
+
+		assert_eq!(
+			inject_gas_counter(module, &rules).unwrap_err(),
+			Error::Instruction {
+				function: 0,
+				offset: 2,
+				instruction: GetGlobal(0),
+				kind: ErrorKind::BlockCostLimit,
+			}
+		);
+	}
+
+	#[test]
+	fn max_block_cost_split() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i32()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							GetGlobal(0),
+							GetGlobal(0),
+							Drop,
+							Drop,
+							Drop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default().with_max_block_cost(3, rules::OversizedBlock::Split);
+		let (injected_module, costs) = inject_gas_counter_with_costs(module, &rules).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(3),
+				Call(0),
+				GetGlobal(0),
+				GetGlobal(0),
+				I64Const(3),
+				Call(0),
+				GetGlobal(0),
+				Drop,
+				Drop,
+				I64Const(1),
+				Call(0),
+				Drop,
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+		assert_eq!(
+			costs[0].blocks,
+			vec![
+				MeteredBlock { range: 0..2, cost: 3 },
+				MeteredBlock { range: 2..5, cost: 3 },
+				MeteredBlock { range: 5..7, cost: 1 },
+			]
+		);
+	}
+
+	#[test]
+	fn max_block_cost_split_instruction() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Nop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let entries = vec![(rules::InstructionType::Nop, rules::Metering::Fixed(10))].into_iter().collect();
+		let rules = rules::Set::new(1, entries).with_max_block_cost(4, rules::OversizedBlock::Split);
+		let injected_module = inject_gas_counter(module, &rules).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(4),
+				Call(0),
+				I64Const(4),
+				Call(0),
+				I64Const(3),
+				Call(0),
+				Nop,
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn max_block_cost_split_merged() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Block(elements::BlockType::NoResult),
+								GetLocal(0),
+								BrIf(0),
+								Nop, Nop, Nop, Nop, Nop, Nop,
+							End,
+							Nop, Nop, Nop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default().with_max_block_cost(5, rules::OversizedBlock::Split);
+		let (injected_module, costs) = inject_gas_counter_with_costs(module, &rules).unwrap();
+
+		// The code after the merged block is charged after its `end`, as the
+		// `br_if` skips the charges inside of it.
+		assert_eq!(
+			&vec![
+				I64Const(5),
+				Call(0),
+				Block(elements::BlockType::NoResult),
+					GetLocal(0),
+					BrIf(0),
+					I64Const(5),
+					Call(0),
+					Nop, Nop, Nop, Nop, Nop,
+					I64Const(1),
+					Call(0),
+					Nop,
+				End,
+				I64Const(3),
+				Call(0),
+				Nop, Nop, Nop,
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+		assert_eq!(
+			costs[0].blocks,
+			vec![
+				MeteredBlock { range: 0..3, cost: 5 },
+				MeteredBlock { range: 3..8, cost: 5 },
+				MeteredBlock { range: 8..10, cost: 1 },
+				MeteredBlock { range: 10..14, cost: 3 },
+			]
+		);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
+	}
+
+	#[test]
+	fn loop_iteration_cost() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i32()
+				.build()
+			.function()
+				.signature().param().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Loop(elements::BlockType::NoResult),
+								GetGlobal(0),
+								Drop,
+							End,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default().with_loop_iteration_cost(5);
+		let injected_module = inject_gas_counter(module, &rules).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(2),
+				Call(0),
+				Loop(elements::BlockType::NoResult),
+					I64Const(8),
+					Call(0),
+					GetGlobal(0),
+					Drop,
+				End,
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn opcode_metering() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							I32Const(1),
+							I32Const(1),
+							I32DivU,
+							Drop,
+							I64Const(1),
+							I64Const(1),
+							I64DivU,
+							Drop,
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let rules = rules::Set::default()
+			.with_opcode_metering(&I64DivU, rules::Metering::Fixed(10))
+			.with_opcode_metering(&I32Const(0), rules::Metering::Fixed(2));
+		let (_, costs) = inject_gas_counter_with_costs(module.clone(), &rules).unwrap();
+
+		// The `end`, 2 + 2 for the i32 constants, 1 + 1 for the i64 ones,
+		// 1 + 10 for the divisions and the drops.
+		assert_eq!(costs[0].blocks, vec![MeteredBlock { range: 0..9, cost: 20 }]);
+
+		let rules = rules::Set::default().with_opcode_metering(&I64DivU, rules::Metering::Forbidden);
+		assert_eq!(
+			inject_gas_counter(module, &rules).unwrap_err(),
+			Error::Instruction {
+				function: 0,
+				offset: 6,
+				instruction: I64DivU,
+				kind: ErrorKind::Forbidden,
+			}
+		);
+	}
+
+	#[test]
+	fn ifelse() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							GetGlobal(0),
+							If(elements::BlockType::NoResult),
+								GetGlobal(0),
+								GetGlobal(0),
+								GetGlobal(0),
+							Else,
+								GetGlobal(0),
+								GetGlobal(0),
+							End,
+							GetGlobal(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(4),
+				Call(0),
+				GetGlobal(0),
+				If(elements::BlockType::NoResult),
+					I64Const(4),
+					Call(0),
+					GetGlobal(0),
+					GetGlobal(0),
+					GetGlobal(0),
+				Else,
+					I64Const(3),
+					Call(0),
+					GetGlobal(0),
+					GetGlobal(0),
+				End,
+				GetGlobal(0),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[0]
+				.code().elements()
+		);
+	}
+
+	#[test]
+	fn call_index() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body().build()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							Call(0),
+							If(elements::BlockType::NoResult),
+								Call(0),
+								Call(0),
+								Call(0),
+							Else,
+								Call(0),
+								Call(0),
+							End,
+							Call(0),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
+
+		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+
+		assert_eq!(
+			&vec![
+				I64Const(4),
+				Call(0),
+				Call(1),
+				If(elements::BlockType::NoResult),
+					I64Const(4),
+					Call(0),
+					Call(1),
+					Call(1),
+					Call(1),
+				Else,
+					I64Const(3),
+					Call(0),
+					Call(1),
+					Call(1),
+				End,
+				Call(1),
+				End
+			][..],
+			injected_module
+				.code_section().expect("function section should exist").bodies()[1]
+				.code().elements()
+		);
+	}
+
+
+	#[test]
+	fn forbidden() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
+			.global()
+				.value_type().i64()
+				.build()
+			.function()
+				.signature().param().i64().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							F32Const(555555),
+							End
+						]
+					))
+					.build()
+				.build()
+			.build();
 
-	let gas_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
-	let total_func = module.functions_space() as u32;
-	let mut need_grow_counter = false;
-	let mut error = false;
+		let rules = rules::Set::default().with_forbidden_floats();
 
-	// Updating calling addresses (all calls to function index >= `gas_func` should be incremented)
-	for section in module.sections_mut() {
-		match section {
-			&mut elements::Section::Code(ref mut code_section) => {
-				for ref mut func_body in code_section.bodies_mut() {
-					update_call_index(func_body.code_mut(), gas_func);
-					if let Err(_) = inject_counter(func_body.code_mut(), rules, gas_func) {
-						error = true;
-						break;
-					}
-					if rules.grow_cost() > 0 {
-						if inject_grow_counter(func_body.code_mut(), total_func) > 0 {
-							need_grow_counter = true;
-						}
-					}
-				}
-			},
-			&mut elements::Section::Export(ref mut export_section) => {
-				for ref mut export in export_section.entries_mut() {
-					if let &mut elements::Internal::Function(ref mut func_index) = export.internal_mut() {
-						if *func_index >= gas_func { *func_index += 1}
-					}
+		match inject_gas_counter(module, &rules) {
+			Err(err) => assert_eq!(
+				err,
+				Error::Instruction {
+					function: 0,
+					offset: 0,
+					instruction: F32Const(555555),
+					kind: ErrorKind::Forbidden,
 				}
-			},
-			&mut elements::Section::Element(ref mut elements_section) => {
-				for ref mut segment in elements_section.entries_mut() {
-					// update all indirect call addresses initial values
-					for func_index in segment.members_mut() {
-						if *func_index >= gas_func { *func_index += 1}
-					}
-				}
-			},
-			_ => { }
+			),
+			Ok(_) => panic!("Should be error because of the forbidden operation"),
 		}
 	}
 
-	if error { return Err(module); }
-
-	if need_grow_counter { Ok(add_grow_counter(module, rules, gas_func)) } else { Ok(module) }
-}
-
-#[cfg(test)]
-mod tests {
+	#[test]
+	fn unbalanced() {
+		use parity_wasm::elements::Instruction::*;
 
-	extern crate wabt;
+		let module = builder::module()
+			.function()
+				.signature().build()
+				.body()
+					.with_instructions(elements::Instructions::new(vec![End, End]))
+					.build()
+				.build()
+			.build();
 
-	use parity_wasm::{serialize, builder, elements};
-	use super::*;
-	use rules;
+		match inject_gas_counter(module, &Default::default()) {
+			Err(err) => assert_eq!(
+				err,
+				Error::Instruction {
+					function: 0,
+					offset: 1,
+					instruction: End,
+					kind: ErrorKind::UnbalancedBlocks,
+				}
+			),
+			Ok(_) => panic!("Should be error because of the unbalanced blocks"),
+		}
+	}
 
 	#[test]
-	fn simple_grow() {
+	fn global_counter() {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
@@ -291,120 +2169,129 @@ mod tests {
 				.build()
 			.build();
 
-		let injected_module = inject_gas_counter(module, &rules::Set::default().with_grow_cost(10000)).unwrap();
+		let rules = rules::Set::default()
+			.with_gas_counter(rules::GasCounter::Global)
+			.with_grow_cost(10000);
+		let injected_module = inject_gas_counter(module, &rules).unwrap();
 
+		assert_eq!(injected_module.import_count(elements::ImportCountType::Function), 0);
+		assert_eq!(injected_module.global_section().unwrap().entries().len(), 2);
 		assert_eq!(
 			&vec![
+				GetGlobal(1),
 				I64Const(3),
-				Call(0),
+				I64LtU,
+				If(elements::BlockType::NoResult),
+					Unreachable,
+				End,
+				GetGlobal(1),
+				I64Const(3),
+				I64Sub,
+				SetGlobal(1),
 				GetGlobal(0),
-				Call(2),
+				Call(1),
 				End
 			][..],
 			injected_module
 				.code_section().expect("function section should exist").bodies()[0]
 				.code().elements()
 		);
+
+		let exports: Vec<_> = injected_module.export_section().unwrap().entries().iter()
+			.map(|entry| (entry.field(), entry.internal().clone()))
+			.collect();
 		assert_eq!(
-			&vec![
-				GetLocal(0),
-				GetLocal(0),
-				I64Const(10000),
-				I64Mul,
-				Call(0),
-				GrowMemory(0),
-				End,
-			][..],
-			injected_module
-				.code_section().expect("function section should exist").bodies()[1]
-				.code().elements()
+			exports,
+			vec![
+				(GAS_LEFT_EXPORT, elements::Internal::Function(2)),
+				(SET_GAS_LEFT_EXPORT, elements::Internal::Function(3)),
+			]
 		);
+		assert!(is_metered(&injected_module, &rules));
 
 		let binary = serialize(injected_module).expect("serialization failed");
 		self::wabt::wasm2wat(&binary).unwrap();
 	}
 
 	#[test]
-	fn grow_no_gas_no_track() {
+	fn custom_import() {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
-			.global()
-				.value_type().i64()
-				.build()
 			.function()
-				.signature().param().i64().build()
+				.signature().build()
 				.body()
-					.with_instructions(elements::Instructions::new(
-						vec![
-							GetGlobal(0),
-							GrowMemory(0),
-							End
-						]
-					))
+					.with_instructions(elements::Instructions::new(vec![Nop, End]))
 					.build()
 				.build()
 			.build();
 
-		let injected_module = inject_gas_counter(module, &rules::Set::default()).unwrap();
+		let rules = rules::Set::default().with_gas_import(rules::GasImport {
+			module: "env".into(),
+			field: "gas".into(),
+			param: elements::ValueType::I32,
+		});
+		let injected_module = inject_gas_counter(module, &rules).unwrap();
 
+		let import = &injected_module.import_section().unwrap().entries()[0];
+		assert_eq!(import.module(), "env");
+		assert_eq!(import.field(), "gas");
 		assert_eq!(
 			&vec![
-				I64Const(3),
+				I32Const(2),
 				Call(0),
-				GetGlobal(0),
-				GrowMemory(0),
+				Nop,
 				End
 			][..],
 			injected_module
 				.code_section().expect("function section should exist").bodies()[0]
 				.code().elements()
 		);
-
-		assert_eq!(injected_module.functions_space(), 2);
-
-		let binary = serialize(injected_module).expect("serialization failed");
-		self::wabt::wasm2wat(&binary).unwrap();
+		assert!(is_metered(&injected_module, &rules));
+		assert!(!is_metered(&injected_module, &Default::default()));
 	}
 
 	#[test]
-	fn simple() {
+	fn reuse_import() {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
-			.global()
-				.value_type().i64()
+			.import()
+				.module("ethereum")
+				.field("useGas")
+				.external().func(0)
 				.build()
 			.function()
 				.signature().param().i64().build()
+				.body().build()
+				.build()
+			.function()
+				.signature().build()
 				.body()
-					.with_instructions(elements::Instructions::new(
-						vec![
-							GetGlobal(0),
-							End
-						]
-					))
+					.with_instructions(elements::Instructions::new(vec![I64Const(100), Call(0), End]))
 					.build()
 				.build()
 			.build();
+		assert!(!is_metered(&module, &Default::default()));
 
 		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
 
+		assert_eq!(injected_module.import_count(elements::ImportCountType::Function), 1);
 		assert_eq!(
 			&vec![
-				I64Const(2),
+				I64Const(3),
+				Call(0),
+				I64Const(100),
 				Call(0),
-				GetGlobal(0),
 				End
 			][..],
 			injected_module
-				.code_section().expect("function section should exist").bodies()[0]
+				.code_section().expect("function section should exist").bodies()[1]
 				.code().elements()
 		);
 	}
 
-	#[test]
-	fn nested() {
+	fn metered_module() -> elements::Module {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
@@ -417,12 +2304,9 @@ mod tests {
 					.with_instructions(elements::Instructions::new(
 						vec![
 							GetGlobal(0),
-							Block(elements::BlockType::NoResult),
-								GetGlobal(0),
-								GetGlobal(0),
+							If(elements::BlockType::NoResult),
 								GetGlobal(0),
 							End,
-							GetGlobal(0),
 							End
 						]
 					))
@@ -430,79 +2314,108 @@ mod tests {
 				.build()
 			.build();
 
-		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+		inject_gas_counter(module, &Default::default()).unwrap()
+	}
 
-		assert_eq!(
-			&vec![
-				I64Const(4),
-				Call(0),
-				GetGlobal(0),
-				Block(elements::BlockType::NoResult),
-					I64Const(4),
-					Call(0),
-					GetGlobal(0),
-					GetGlobal(0),
-					GetGlobal(0),
-				End,
-				GetGlobal(0),
-				End
-			][..],
-			injected_module
-				.code_section().expect("function section should exist").bodies()[0]
-				.code().elements()
-		);
+	#[test]
+	fn already_metered_reject() {
+		assert!(is_metered(&metered_module(), &Default::default()));
+
+		match inject_gas_counter(metered_module(), &Default::default()) {
+			Err(err) => assert_eq!(err, Error::AlreadyMetered),
+			Ok(_) => panic!("Should be error because the module is already metered"),
+		}
 	}
 
 	#[test]
-	fn ifelse() {
+	fn already_metered_grow() {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
-			.global()
-				.value_type().i64()
+			.memory().build()
+			.function()
+				.signature().return_type().i32().build()
+				.body()
+					.with_instructions(elements::Instructions::new(
+						vec![
+							I32Const(1),
+							GrowMemory(0),
+							End
+						]
+					))
+					.build()
 				.build()
+			.build();
+		let rules = rules::Set::default().with_grow_cost(10);
+		let metered_module = inject_gas_counter(module, &rules).unwrap();
+
+		// The helper charging `grow_memory` doesn't start with a charge.
+		assert!(is_metered(&metered_module, &rules));
+		assert_eq!(inject_gas_counter(metered_module, &rules), Err(Error::AlreadyMetered));
+	}
+
+	#[test]
+	fn already_metered_stack_limited() {
+		use parity_wasm::elements::Instruction::*;
+
+		let module = builder::module()
 			.function()
-				.signature().param().i64().build()
+				.signature().param().i32().build()
 				.body()
 					.with_instructions(elements::Instructions::new(
 						vec![
-							GetGlobal(0),
-							If(elements::BlockType::NoResult),
-								GetGlobal(0),
-								GetGlobal(0),
-								GetGlobal(0),
-							Else,
-								GetGlobal(0),
-								GetGlobal(0),
-							End,
-							GetGlobal(0),
+							GetLocal(0),
+							Drop,
 							End
 						]
 					))
 					.build()
 				.build()
+			.export()
+				.field("call")
+				.internal().func(0)
+				.build()
 			.build();
+		let rules = rules::Set::default();
+		let module = inject_gas_counter(module, &rules).unwrap();
+		let module = stack_height::inject_limiter(module, 1024).unwrap();
 
-		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+		// The thunk of the exported function doesn't start with a charge.
+		assert!(is_metered(&module, &rules));
+		assert_eq!(inject_gas_counter(module, &rules), Err(Error::AlreadyMetered));
+	}
+
+	#[test]
+	fn already_metered_pass_through() {
+		let rules = rules::Set::default().with_already_metered(rules::AlreadyMetered::PassThrough);
+		let injected_module = inject_gas_counter(metered_module(), &rules).unwrap();
+
+		assert_eq!(
+			serialize(metered_module()).unwrap(),
+			serialize(injected_module).unwrap()
+		);
+	}
+
+	#[test]
+	fn already_metered_remeter() {
+		use parity_wasm::elements::Instruction::*;
 
+		let rules = rules::Set::default()
+			.with_already_metered(rules::AlreadyMetered::Remeter)
+			.with_grow_cost(1);
+		let injected_module = inject_gas_counter(metered_module(), &rules).unwrap();
+
+		assert_eq!(injected_module.import_count(elements::ImportCountType::Function), 1);
 		assert_eq!(
 			&vec![
-				I64Const(4),
+				I64Const(3),
 				Call(0),
 				GetGlobal(0),
 				If(elements::BlockType::NoResult),
-					I64Const(4),
+					I64Const(2),
 					Call(0),
 					GetGlobal(0),
-					GetGlobal(0),
-					GetGlobal(0),
-				Else,
-					I64Const(3),
-					Call(0),
-					GetGlobal(0),
-					GetGlobal(0),
 				End,
-				GetGlobal(0),
 				End
 			][..],
 			injected_module
@@ -512,94 +2425,81 @@ mod tests {
 	}
 
 	#[test]
-	fn call_index() {
+	fn already_metered_remeter_split() {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
 			.global()
-				.value_type().i64()
-				.build()
-			.function()
-				.signature().param().i64().build()
-				.body().build()
+				.value_type().i32()
 				.build()
 			.function()
-				.signature().param().i64().build()
+				.signature().build()
 				.body()
 					.with_instructions(elements::Instructions::new(
 						vec![
-							Call(0),
-							If(elements::BlockType::NoResult),
-								Call(0),
-								Call(0),
-								Call(0),
-							Else,
-								Call(0),
-								Call(0),
-							End,
-							Call(0),
+							GetGlobal(0),
+							GetGlobal(0),
+							GetGlobal(0),
+							Drop,
+							Drop,
+							Drop,
 							End
 						]
 					))
 					.build()
 				.build()
 			.build();
+		let rules = || rules::Set::default().with_max_block_cost(3, rules::OversizedBlock::Split);
+		let metered_module = inject_gas_counter(module.clone(), &rules()).unwrap();
 
-		let injected_module = inject_gas_counter(module, &Default::default()).unwrap();
+		let injected_module = inject_gas_counter(
+			metered_module,
+			&rules().with_already_metered(rules::AlreadyMetered::Remeter),
+		).unwrap();
 
+		// The charges in the middle of the split block are removed as well.
+		let expected_module = inject_gas_counter(module, &rules()).unwrap();
 		assert_eq!(
-			&vec![
-				I64Const(4),
-				Call(0),
-				Call(1),
-				If(elements::BlockType::NoResult),
-					I64Const(4),
-					Call(0),
-					Call(1),
-					Call(1),
-					Call(1),
-				Else,
-					I64Const(3),
-					Call(0),
-					Call(1),
-					Call(1),
-				End,
-				Call(1),
-				End
-			][..],
-			injected_module
-				.code_section().expect("function section should exist").bodies()[1]
-				.code().elements()
+			injected_module.code_section().expect("code section should exist").bodies(),
+			expected_module.code_section().expect("code section should exist").bodies()
 		);
 	}
 
-
 	#[test]
-	fn forbidden() {
+	fn already_metered_remeter_grow() {
 		use parity_wasm::elements::Instruction::*;
 
 		let module = builder::module()
-			.global()
-				.value_type().i64()
-				.build()
+			.memory().build()
 			.function()
-				.signature().param().i64().build()
+				.signature().return_type().i32().build()
 				.body()
 					.with_instructions(elements::Instructions::new(
 						vec![
-							F32Const(555555),
+							I32Const(1),
+							GrowMemory(0),
 							End
 						]
 					))
 					.build()
 				.build()
 			.build();
+		let metered_module = inject_gas_counter(module.clone(), &rules::Set::default().with_grow_cost(10)).unwrap();
 
-		let rules = rules::Set::default().with_forbidden_floats();
+		let rules = rules::Set::default()
+			.with_already_metered(rules::AlreadyMetered::Remeter)
+			.with_grow_cost(20);
+		let injected_module = inject_gas_counter(metered_module, &rules).unwrap();
 
-		if let Err(_) = inject_gas_counter(module, &rules) { }
-		else { panic!("Should be error because of the forbidden operation")}
+		// The old helper is replaced, instead of being charged by the new one.
+		let expected_module = inject_gas_counter(module, &rules).unwrap();
+		assert_eq!(injected_module.functions_space(), 3);
+		assert_eq!(
+			injected_module.code_section().expect("code section should exist").bodies(),
+			expected_module.code_section().expect("code section should exist").bodies()
+		);
 
+		let binary = serialize(injected_module).expect("serialization failed");
+		self::wabt::Module::read_binary(&binary, &Default::default()).unwrap().validate().unwrap();
 	}
-
 }
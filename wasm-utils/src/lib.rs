@@ -1,3 +1,10 @@
+//! Collection of WASM utilities used in Parity and WASM contract development.
+//!
+//! All transformations are deterministic: the same module and rules produce
+//! byte-for-byte identical output, with or without the `std` feature. For this
+//! reason only ordered collections (`BTreeMap` and `BTreeSet`) are used, never
+//! hash based ones whose iteration order may vary.
+
 #![cfg_attr(not(feature = "std"), no_std)]
 #![cfg_attr(not(feature = "std"), feature(alloc))]
 
@@ -5,6 +12,11 @@
 #[macro_use]
 extern crate alloc;
 
+// Modules import from `crate::std`, which is either the standard library or
+// the `std` module below.
+#[cfg(feature = "std")]
+extern crate std;
+
 extern crate parity_wasm;
 extern crate byteorder;
 #[macro_use] extern crate log;
@@ -21,23 +33,30 @@ mod gas;
 mod symbols;
 mod ext;
 mod pack;
+mod remap;
 mod runtime_type;
+mod schedule;
 
 pub mod stack_height;
 
 pub use build::{build, SourceTarget, Error as BuildError};
 pub use optimizer::{optimize, Error as OptimizerError};
-pub use gas::inject_gas_counter;
+pub use gas::{
+	inject_gas_counter, inject_gas_counter_with_costs, is_metered, FunctionCosts, MeteredBlock,
+	Error as GasError, ErrorKind as GasErrorKind,
+};
 pub use ext::{externalize, externalize_mem, underscore_funcs, ununderscore_funcs, shrink_unknown_stack};
 pub use pack::{pack_instance, Error as PackingError};
+pub use remap::{remap_function_indices, shift_function_indices};
 pub use runtime_type::inject_runtime_type;
+pub use schedule::{Schedule, Error as ScheduleError};
 
 #[cfg(not(feature = "std"))]
 mod std {
 	pub use core::*;
-	pub use alloc::{vec, string, boxed, borrow};
+	pub use ::alloc::{vec, string, borrow};
 
 	pub mod collections {
-		pub use alloc::collections::{BTreeMap, BTreeSet};
+		pub use ::alloc::collections::{BTreeMap, BTreeSet};
 	}
 }
@@ -1,6 +1,6 @@
-use std::fmt;
-use std::vec::Vec;
-use std::borrow::ToOwned;
+use crate::std::fmt;
+use crate::std::vec::Vec;
+use crate::std::borrow::ToOwned;
 
 use parity_wasm::elements::{
     self, Section, DataSection, Instruction, DataSegment, InitExpr, Internal, External,
@@ -8,7 +8,7 @@ use parity_wasm::elements::{
 };
 use parity_wasm::builder;
 use super::{CREATE_SYMBOL, CALL_SYMBOL, RET_SYMBOL};
-use super::gas::update_call_index;
+use super::remap::shift_function_indices;
 
 /// Pack error.
 ///
@@ -111,31 +111,7 @@ pub fn pack_instance(raw_module: Vec<u8>, mut ctor_module: elements::Module) ->
 
             let ret_func = ctor_module.import_count(ImportCountType::Function) as u32 - 1;
 
-            for section in ctor_module.sections_mut() {
-                match *section {
-                    elements::Section::Code(ref mut code_section) => {
-                        for ref mut func_body in code_section.bodies_mut() {
-                            update_call_index(func_body.code_mut(), ret_func);
-                        }
-                    },
-                    elements::Section::Export(ref mut export_section) => {
-                        for ref mut export in export_section.entries_mut() {
-                            if let &mut elements::Internal::Function(ref mut func_index) = export.internal_mut() {
-                                if *func_index >= ret_func { *func_index += 1}
-                            }
-                        }
-                    },
-                    elements::Section::Element(ref mut elements_section) => {
-                        for ref mut segment in elements_section.entries_mut() {
-                            // update all indirect call addresses initial values
-                            for func_index in segment.members_mut() {
-                                if *func_index >= ret_func { *func_index += 1}
-                            }
-                        }
-                    },
-                    _ => { }
-                }
-            }
+            shift_function_indices(&mut ctor_module, ret_func, 1);
 
             create_func_id += 1;
             ret_func
@@ -0,0 +1,237 @@
+//! Rewriting of function index references.
+//!
+//! Imported functions come first in the function index space, so adding an import
+//! moves every function defined by the module. All references to those functions
+//! have to be updated, otherwise the module silently calls the wrong code.
+
+use crate::std::mem;
+use parity_wasm::elements::{self, Section, Internal, Instruction, NameSection, IndexMap};
+
+const NAME_SECTION: &str = "name";
+
+/// Rewrites every function index referenced by the module with `remap`.
+///
+/// This covers calls in function bodies, function exports, table element segments,
+/// the start function, and the function and local names of the `name` section.
+/// A raw `name` section is parsed first, see `parse_names`.
+pub fn remap_function_indices<F: Fn(u32) -> u32>(module: &mut elements::Module, remap: F) {
+	parse_names(module);
+
+	for section in module.sections_mut().iter_mut() {
+		match *section {
+			Section::Code(ref mut code_section) => {
+				for func_body in code_section.bodies_mut() {
+					for instruction in func_body.code_mut().elements_mut().iter_mut() {
+						if let Instruction::Call(ref mut call_index) = *instruction {
+							*call_index = remap(*call_index);
+						}
+					}
+				}
+			},
+			Section::Export(ref mut export_section) => {
+				for export in export_section.entries_mut() {
+					if let Internal::Function(ref mut func_index) = *export.internal_mut() {
+						*func_index = remap(*func_index);
+					}
+				}
+			},
+			Section::Element(ref mut elements_section) => {
+				for segment in elements_section.entries_mut() {
+					for func_index in segment.members_mut() {
+						*func_index = remap(*func_index);
+					}
+				}
+			},
+			Section::Start(ref mut func_index) => {
+				*func_index = remap(*func_index);
+			},
+			Section::Name(ref mut name_section) => {
+				remap_name_section(name_section, &remap);
+			},
+			_ => { }
+		}
+	}
+}
+
+/// Shifts the indices of all functions starting at `index` by `count`.
+///
+/// Call this after inserting `count` functions at `index`, e.g. `count` new
+/// imported functions after `index` existing ones.
+pub fn shift_function_indices(module: &mut elements::Module, index: u32, count: u32) {
+	remap_function_indices(module, |func_index| {
+		if func_index >= index { func_index + count } else { func_index }
+	});
+}
+
+/// Parses a raw `name` section with `Module::parse_names`, so that its function
+/// indices can be remapped.
+///
+/// parity-wasm only parses the first subsection of the names, the following ones
+/// are dropped. A `name` section that cannot be decoded is removed, as its names
+/// can no longer be trusted.
+fn parse_names(module: &mut elements::Module) {
+	let has_raw_names = module.sections().iter().any(|section| match *section {
+		Section::Custom(ref custom) => custom.name() == NAME_SECTION,
+		_ => false,
+	});
+	if !has_raw_names {
+		return;
+	}
+
+	*module = match mem::replace(module, elements::Module::default()).parse_names() {
+		Ok(module) => module,
+		Err((_, mut module)) => {
+			module.sections_mut().retain(|section| match *section {
+				Section::Custom(ref custom) => custom.name() != NAME_SECTION,
+				_ => true,
+			});
+			module
+		},
+	};
+}
+
+fn remap_name_section<F: Fn(u32) -> u32>(name_section: &mut NameSection, remap: &F) {
+	match *name_section {
+		NameSection::Function(ref mut function_names) => {
+			let names: IndexMap<_> = function_names.names().iter()
+				.map(|(func_index, name)| (remap(func_index), name.clone()))
+				.collect();
+			*function_names.names_mut() = names;
+		},
+		NameSection::Local(ref mut local_names) => {
+			let names: IndexMap<_> = local_names.local_names().iter()
+				.map(|(func_index, names)| (remap(func_index), names.clone()))
+				.collect();
+			*local_names.local_names_mut() = names;
+		},
+		_ => { }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	extern crate wabt;
+
+	use crate::std::borrow::ToOwned;
+	use crate::std::vec::Vec;
+	use parity_wasm::elements;
+	use parity_wasm::elements::Instruction::*;
+	use super::*;
+
+	fn parse_wat(source: &str) -> elements::Module {
+		let module_bytes = wabt::wat2wasm(source).unwrap();
+		elements::deserialize_buffer(module_bytes.as_ref()).unwrap()
+	}
+
+	fn set_raw_names(module: &mut elements::Module, payload: Vec<u8>) {
+		let mut custom = elements::CustomSection::default();
+		*custom.name_mut() = NAME_SECTION.to_owned();
+		*custom.payload_mut() = payload;
+
+		module.sections_mut().retain(|section| match *section {
+			Section::Custom(ref custom) => custom.name() != NAME_SECTION,
+			_ => true,
+		});
+		module.sections_mut().push(Section::Custom(custom));
+	}
+
+	fn raw_names(module: &elements::Module) -> Option<&[u8]> {
+		module.sections().iter().filter_map(|section| match *section {
+			Section::Custom(ref custom) if custom.name() == NAME_SECTION => Some(custom.payload()),
+			_ => None,
+		}).next()
+	}
+
+	fn function_names(module: &elements::Module) -> Vec<(u32, &str)> {
+		match module.names_section() {
+			Some(&NameSection::Function(ref names)) =>
+				names.names().iter().map(|(func_index, name)| (func_index, name.as_str())).collect(),
+			_ => panic!("function name section should exist"),
+		}
+	}
+
+	#[test]
+	fn shifts_all_references() {
+		let mut module = parse_wat(r#"
+(module
+	(import "env" "f" (func $f))
+	(table 2 anyfunc)
+	(elem (i32.const 0) $f $b)
+	(func $a
+		call $f
+		call $b)
+	(func $b
+		call $a)
+	(export "a" (func $a))
+	(export "f" (func $f))
+	(start $b))
+"#);
+
+		shift_function_indices(&mut module, 1, 2);
+
+		let code = module.code_section().unwrap();
+		assert_eq!(code.bodies()[0].code().elements(), &[Call(0), Call(4), End][..]);
+		assert_eq!(code.bodies()[1].code().elements(), &[Call(3), End][..]);
+
+		let exports: Vec<_> = module.export_section().unwrap().entries().iter()
+			.map(|export| export.internal().clone())
+			.collect();
+		assert_eq!(exports, vec![Internal::Function(3), Internal::Function(0)]);
+
+		assert_eq!(module.elements_section().unwrap().entries()[0].members(), &[0, 4][..]);
+		assert_eq!(module.start_section(), Some(4));
+	}
+
+	#[test]
+	fn remaps_raw_names() {
+		let mut module = parse_wat(r#"
+(module
+	(import "env" "f" (func $f))
+	(func $a (param i32))
+	(func $b))
+"#);
+		set_raw_names(&mut module, vec![
+			// function names: 0 => "f", 1 => "a", 2 => "b"
+			1, 10, 3, 0, 1, b'f', 1, 1, b'a', 2, 1, b'b',
+		]);
+
+		// Move $a after $b.
+		remap_function_indices(&mut module, |func_index| match func_index {
+			1 => 3,
+			other => other,
+		});
+
+		assert_eq!(raw_names(&module), None);
+		assert_eq!(function_names(&module), vec![(0, "f"), (2, "b"), (3, "a")]);
+	}
+
+	#[test]
+	fn malformed_raw_names() {
+		let mut module = parse_wat(r#"(module (func $a))"#);
+		// function names claiming two entries, but holding only one
+		set_raw_names(&mut module, vec![1, 5, 2, 0, 1, b'a']);
+
+		shift_function_indices(&mut module, 0, 1);
+
+		assert_eq!(raw_names(&module), None);
+		assert_eq!(module.names_section(), None);
+	}
+
+	#[test]
+	fn parsed_names() {
+		let mut module = parse_wat(r#"
+(module
+	(import "env" "f" (func $f))
+	(func $a))
+"#);
+		set_raw_names(&mut module, vec![
+			1, 7, 2, 0, 1, b'f', 1, 1, b'a',
+		]);
+		let mut module = module.parse_names().unwrap();
+
+		shift_function_indices(&mut module, 1, 1);
+
+		assert_eq!(function_names(&module), vec![(0, "f"), (2, "a")]);
+	}
+}
@@ -1,10 +1,13 @@
-#[cfg(features = "std")]
-use std::collections::{HashMap as Map};
-#[cfg(not(features = "std"))]
-use std::collections::{BTreeMap as Map};
+use crate::std::collections::{BTreeMap as Map};
+
+use crate::std::mem;
+use crate::std::string::String;
+use crate::std::vec::Vec;
 
 use parity_wasm::elements;
 
+use crate::Schedule;
+
 pub struct UnknownInstruction;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -14,6 +17,100 @@ pub enum Metering {
     Fixed(u32),
 }
 
+/// Gas charged at runtime, depending on the value of an operand of an instruction.
+///
+/// Instructions with a dynamic cost are replaced by a call to a generated
+/// helper function, which charges `cost` per unit of the operand before
+/// executing the instruction.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct DynamicCost {
+    /// Index of the operand, the first operand pushed being 0. It must be a
+    /// size, i.e. the number of pages of `grow_memory`; the gas injection fails
+    /// with `ErrorKind::UnsupportedDynamicCost` for any other operand.
+    pub operand: u32,
+    /// Cost per unit of the operand.
+    pub cost: u32,
+}
+
+/// Price of a memory depending on its size in pages, see `Set::with_grow_curve`.
+///
+/// Growing the memory is charged the price of its new size minus the price of
+/// its current size, like the memory expansion of the EVM.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum GrowCost {
+    /// `pages * cost`, i.e. a constant cost per page grown.
+    Linear(u32),
+    /// `pages * linear + pages * pages / quadratic_divisor`.
+    ///
+    /// The EVM prices its memory with a `linear` cost of 3 and a
+    /// `quadratic_divisor` of 512, per word. A divisor of 0 is treated as 1.
+    Quadratic { linear: u32, quadratic_divisor: u32 },
+    /// Steps of `(first page, cost)`: every page from the first page of a step
+    /// on costs the cost of the step, until the next step.
+    ///
+    /// Pages before the first step are free.
+    Steps(Vec<(u32, u32)>),
+}
+
+/// What to do with a module which already imports the gas function,
+/// e.g. because it was metered before.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AlreadyMetered {
+    /// Fail the injection.
+    Reject,
+    /// Return the module unchanged.
+    PassThrough,
+    /// Strip the existing gas counters and inject new ones.
+    Remeter,
+}
+
+/// What to do with a block whose static cost exceeds the maximum,
+/// see `Set::with_max_block_cost`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OversizedBlock {
+    /// Fail the injection.
+    Reject,
+    /// Charge the block in several parts, starting a new part before the
+    /// instruction which would exceed the maximum.
+    ///
+    /// A single instruction costing more than the maximum is charged with
+    /// several consecutive charges.
+    Split,
+}
+
+/// The function imported to charge gas, see `GasCounter::Import`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GasImport {
+    pub module: String,
+    pub field: String,
+    /// Type of the cost parameter, either `I32` or `I64`.
+    pub param: elements::ValueType,
+}
+
+impl Default for GasImport {
+    fn default() -> Self {
+        GasImport {
+            module: "ethereum".into(),
+            field: "useGas".into(),
+            param: elements::ValueType::I64,
+        }
+    }
+}
+
+/// How the injected code charges gas.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum GasCounter {
+    /// Call the imported gas function at the start of every block, `ethereum.useGas`
+    /// unless configured otherwise with `Set::with_gas_import`.
+    Import,
+    /// Subtract from an injected mutable global at the start of every block,
+    /// trapping if not enough gas is left.
+    ///
+    /// The global is seeded and read through the exported `set_gas_left`
+    /// and `gas_left` functions.
+    Global,
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub enum InstructionType {
     Bit,
@@ -27,6 +124,8 @@ pub enum InstructionType {
     Local,
     Global,
     ControlFlow,
+    BrTable,
+    CallIndirect,
     IntegerComparsion,
     FloatComparsion,
     Float,
@@ -39,7 +138,7 @@ pub enum InstructionType {
     GrowMemory,
 }
 
-impl ::std::str::FromStr for InstructionType {
+impl crate::std::str::FromStr for InstructionType {
     type Err = UnknownInstruction;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -51,9 +150,12 @@ impl ::std::str::FromStr for InstructionType {
             "load" => Ok(InstructionType::Load),
             "store" => Ok(InstructionType::Store),
             "const" => Ok(InstructionType::Const),
+            "float_const" => Ok(InstructionType::FloatConst),
             "local" => Ok(InstructionType::Local),
             "global" => Ok(InstructionType::Global),
             "flow" => Ok(InstructionType::ControlFlow),
+            "br_table" => Ok(InstructionType::BrTable),
+            "call_indirect" => Ok(InstructionType::CallIndirect),
             "integer_comp" => Ok(InstructionType::IntegerComparsion),
             "float_comp" => Ok(InstructionType::FloatComparsion),
             "float" => Ok(InstructionType::Float),
@@ -69,6 +171,38 @@ impl ::std::str::FromStr for InstructionType {
     }
 }
 
+/// Writes the name understood by `from_str`.
+impl crate::std::fmt::Display for InstructionType {
+    fn fmt(&self, f: &mut crate::std::fmt::Formatter) -> crate::std::fmt::Result {
+        let name = match *self {
+            InstructionType::Bit => "bit",
+            InstructionType::Add => "add",
+            InstructionType::Mul => "mul",
+            InstructionType::Div => "div",
+            InstructionType::Load => "load",
+            InstructionType::Store => "store",
+            InstructionType::Const => "const",
+            InstructionType::FloatConst => "float_const",
+            InstructionType::Local => "local",
+            InstructionType::Global => "global",
+            InstructionType::ControlFlow => "flow",
+            InstructionType::BrTable => "br_table",
+            InstructionType::CallIndirect => "call_indirect",
+            InstructionType::IntegerComparsion => "integer_comp",
+            InstructionType::FloatComparsion => "float_comp",
+            InstructionType::Float => "float",
+            InstructionType::Conversion => "conversion",
+            InstructionType::FloatConversion => "float_conversion",
+            InstructionType::Reinterpretation => "reinterpret",
+            InstructionType::Unreachable => "unreachable",
+            InstructionType::Nop => "nop",
+            InstructionType::CurrentMemory => "current_mem",
+            InstructionType::GrowMemory => "grow_mem",
+        };
+        f.write_str(name)
+    }
+}
+
 impl InstructionType {
     pub fn op(instruction: &elements::Instruction) -> Self {
         use parity_wasm::elements::Instruction::*;
@@ -83,10 +217,10 @@ impl InstructionType {
             End => InstructionType::ControlFlow,
             Br(_) => InstructionType::ControlFlow,
             BrIf(_) => InstructionType::ControlFlow,
-            BrTable(_, _) => InstructionType::ControlFlow,
+            BrTable(_, _) => InstructionType::BrTable,
             Return => InstructionType::ControlFlow,
             Call(_) => InstructionType::ControlFlow,
-            CallIndirect(_, _) => InstructionType::ControlFlow,
+            CallIndirect(_, _) => InstructionType::CallIndirect,
             Drop => InstructionType::ControlFlow,
             Select => InstructionType::ControlFlow,
 
@@ -270,7 +404,16 @@ impl InstructionType {
 pub struct Set {
     regular: u32,
     entries: Map<InstructionType, Metering>,
-    grow: u32,
+    opcodes: Vec<(mem::Discriminant<elements::Instruction>, Metering)>,
+    dynamic: Map<InstructionType, DynamicCost>,
+    grow_curve: Option<GrowCost>,
+    already_metered: AlreadyMetered,
+    gas_counter: GasCounter,
+    gas_import: GasImport,
+    precise: bool,
+    br_table_target: u32,
+    max_block_cost: Option<(u32, OversizedBlock)>,
+    loop_iteration_cost: u32,
 }
 
 impl Default for Set {
@@ -278,30 +421,230 @@ impl Default for Set {
         Set {
             regular: 1,
             entries: Map::new(),
-            grow: 0,
+            opcodes: Vec::new(),
+            dynamic: Map::new(),
+            grow_curve: None,
+            already_metered: AlreadyMetered::Reject,
+            gas_counter: GasCounter::Import,
+            gas_import: GasImport::default(),
+            precise: false,
+            br_table_target: 0,
+            max_block_cost: None,
+            loop_iteration_cost: 0,
         }
     }
 }
 
 impl Set {
     pub fn new(regular: u32, entries: Map<InstructionType, Metering>) -> Self {
-        Set { regular: regular, entries: entries, grow: 0 }
+        Set {
+            regular: regular,
+            entries: entries,
+            opcodes: Vec::new(),
+            dynamic: Map::new(),
+            grow_curve: None,
+            already_metered: AlreadyMetered::Reject,
+            gas_counter: GasCounter::Import,
+            gas_import: GasImport::default(),
+            precise: false,
+            br_table_target: 0,
+            max_block_cost: None,
+            loop_iteration_cost: 0,
+        }
+    }
+
+    /// The rules of a named preset, see `Schedule::preset`.
+    ///
+    /// The `pwasm` preset also charges through the `env.gas` import of the
+    /// Parity runtime, which takes an `i32` cost.
+    pub fn with_preset(name: &str) -> Result<Self, ()> {
+        let rules = Schedule::preset(name)?.to_rules();
+        Ok(match name {
+            "pwasm" => rules.with_gas_import(GasImport {
+                module: "env".into(),
+                field: "gas".into(),
+                param: elements::ValueType::I32,
+            }),
+            _ => rules,
+        })
     }
 
+    /// Cost of an instruction, or an error if it is forbidden.
+    ///
+    /// The metering of the opcode, if overridden, or else of the instruction class
+    /// is increased by the costs depending on the immediates of the instruction,
+    /// e.g. the number of `br_table` targets.
     pub fn process(&self, instruction: &elements::Instruction) -> Result<u32, ()>  {
-        match self.entries.get(&InstructionType::op(instruction)).map(|x| *x) {
-            None | Some(Metering::Regular) => Ok(self.regular),
-            Some(Metering::Forbidden) => Err(()),
-            Some(Metering::Fixed(val)) => Ok(val),
+        let metering = self.opcode_metering(instruction)
+            .or_else(|| self.entries.get(&InstructionType::op(instruction)).map(|x| *x));
+        let cost = match metering {
+            None | Some(Metering::Regular) => self.regular,
+            Some(Metering::Forbidden) => return Err(()),
+            Some(Metering::Fixed(val)) => val,
+        };
+
+        match *instruction {
+            elements::Instruction::BrTable(ref targets, _) => {
+                // The default target counts as well.
+                let targets = targets.len() as u32 + 1;
+                Ok(cost.saturating_add(targets.saturating_mul(self.br_table_target)))
+            },
+            _ => Ok(cost),
         }
     }
 
+    /// Metering of the opcode of an instruction, if overridden with `with_opcode_metering`.
+    pub fn opcode_metering(&self, instruction: &elements::Instruction) -> Option<Metering> {
+        let opcode = mem::discriminant(instruction);
+        self.opcodes.iter().find(|&&(op, _)| op == opcode).map(|&(_, metering)| metering)
+    }
+
+    /// Meter the opcode of `instruction` with `metering` instead of the metering
+    /// of its class, e.g. to charge `i64.div_u` more than `i32.div_u`.
+    ///
+    /// The immediates of `instruction` are ignored.
+    pub fn with_opcode_metering(mut self, instruction: &elements::Instruction, metering: Metering) -> Self {
+        let opcode = mem::discriminant(instruction);
+        self.opcodes.retain(|&(op, _)| op != opcode);
+        self.opcodes.push((opcode, metering));
+        self
+    }
+
+    /// Dynamic cost of an instruction, if any.
+    pub fn dynamic_cost(&self, instruction: &elements::Instruction) -> Option<DynamicCost> {
+        self.dynamic.get(&InstructionType::op(instruction)).map(|x| *x)
+    }
+
+    /// Charge instructions of the class `instruction_type` depending on one of
+    /// their operands, in addition to their regular cost.
+    ///
+    /// A dynamic cost of `grow_memory` replaces the grow curve, if any.
+    pub fn with_dynamic_cost(mut self, instruction_type: InstructionType, cost: DynamicCost) -> Self {
+        if instruction_type == InstructionType::GrowMemory {
+            self.grow_curve = None;
+        }
+        self.dynamic.insert(instruction_type, cost);
+        self
+    }
+
+    /// Cost per page of memory grown, or 0 if growing is free or priced by a
+    /// curve, see `grow_curve`.
     pub fn grow_cost(&self) -> u32 {
-        self.grow
+        self.dynamic.get(&InstructionType::GrowMemory).map(|dynamic| dynamic.cost).unwrap_or(0)
     }
 
+    /// Charge `val` per page of memory grown.
     pub fn with_grow_cost(mut self, val: u32) -> Self {
-        self.grow = val;
+        self.grow_curve = None;
+        if val > 0 {
+            self.dynamic.insert(InstructionType::GrowMemory, DynamicCost { operand: 0, cost: val });
+        } else {
+            self.dynamic.remove(&InstructionType::GrowMemory);
+        }
+        self
+    }
+
+    /// Curve pricing the memory, if set with `with_grow_curve` and not linear.
+    pub fn grow_curve(&self) -> Option<&GrowCost> {
+        self.grow_curve.as_ref()
+    }
+
+    /// Charge growing the memory by the difference of the prices of its new and
+    /// its current size on `curve`.
+    ///
+    /// A linear curve is the same as `with_grow_cost`. Other curves need the
+    /// current size of the memory, so `grow_memory` is replaced by a helper
+    /// reading it with `current_memory`. Growing the memory beyond 65536 pages,
+    /// which always fails, is not charged.
+    pub fn with_grow_curve(mut self, curve: GrowCost) -> Self {
+        match curve {
+            GrowCost::Linear(val) => self.with_grow_cost(val),
+            GrowCost::Steps(mut steps) => {
+                steps.sort_by_key(|&(first_page, _)| first_page);
+                self.dynamic.remove(&InstructionType::GrowMemory);
+                self.grow_curve = Some(GrowCost::Steps(steps));
+                self
+            },
+            curve => {
+                self.dynamic.remove(&InstructionType::GrowMemory);
+                self.grow_curve = Some(curve);
+                self
+            },
+        }
+    }
+
+    pub fn already_metered(&self) -> AlreadyMetered {
+        self.already_metered
+    }
+
+    pub fn with_already_metered(mut self, policy: AlreadyMetered) -> Self {
+        self.already_metered = policy;
+        self
+    }
+
+    pub fn gas_counter(&self) -> GasCounter {
+        self.gas_counter
+    }
+
+    pub fn with_gas_counter(mut self, counter: GasCounter) -> Self {
+        self.gas_counter = counter;
+        self
+    }
+
+    pub fn br_table_target_cost(&self) -> u32 {
+        self.br_table_target
+    }
+
+    /// Charge `val` for every target of a `br_table`, including the default one.
+    pub fn with_br_table_target_cost(mut self, val: u32) -> Self {
+        self.br_table_target = val;
+        self
+    }
+
+    pub fn gas_import(&self) -> &GasImport {
+        &self.gas_import
+    }
+
+    pub fn with_gas_import(mut self, import: GasImport) -> Self {
+        self.gas_import = import;
+        self
+    }
+
+    pub fn precise(&self) -> bool {
+        self.precise
+    }
+
+    /// Charge every straight-line segment when it is entered, instead of
+    /// charging the full cost of a block at its start.
+    ///
+    /// Segments end at control flow and branch instructions, so code skipped by
+    /// a branch is not charged, at the cost of more charges.
+    pub fn with_precise_metering(mut self) -> Self {
+        self.precise = true;
+        self
+    }
+
+    pub fn max_block_cost(&self) -> Option<(u32, OversizedBlock)> {
+        self.max_block_cost
+    }
+
+    /// Limit the static cost of a block, or of a segment with precise metering,
+    /// to `max`, handling blocks above it according to `policy`.
+    ///
+    /// A `max` of 0 is treated as 1.
+    pub fn with_max_block_cost(mut self, max: u32, policy: OversizedBlock) -> Self {
+        self.max_block_cost = Some((crate::std::cmp::max(max, 1), policy));
+        self
+    }
+
+    pub fn loop_iteration_cost(&self) -> u32 {
+        self.loop_iteration_cost
+    }
+
+    /// Charge `val` on every iteration of a loop, in addition to the cost of
+    /// its instructions.
+    pub fn with_loop_iteration_cost(mut self, val: u32) -> Self {
+        self.loop_iteration_cost = val;
         self
     }
 
@@ -0,0 +1,434 @@
+use crate::std::fmt;
+use crate::std::collections::{BTreeMap, BTreeSet};
+use crate::std::string::String;
+use crate::std::vec::Vec;
+
+use crate::rules::{self, InstructionType, Metering};
+
+/// Error of parsing a schedule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// A line of the schedule is invalid.
+	Line {
+		/// Number of the line, starting at 1.
+		line: usize,
+		reason: &'static str,
+	},
+	/// A required key is missing.
+	MissingKey(&'static str),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::Line { line, reason } => write!(f, "line {}: {}", line, reason),
+			Error::MissingKey(key) => write!(f, "missing `{}`", key),
+		}
+	}
+}
+
+/// Gas costs of a metering, which can be stored in a file.
+///
+/// Schedules are written in a small subset of TOML, one `key = value` per line:
+///
+/// ```toml
+/// # Optional, start from a preset, see `Schedule::preset`. The keys below override it.
+/// preset = "ewasm"
+/// # Cost of any instruction class not listed below.
+/// regular = 1
+/// # Cost of growing the memory by one page (64KiB).
+/// memory_page = 262144
+/// # Optional, prices the memory quadratically if not 0, see `rules::GrowCost::Quadratic`,
+/// # with `memory_page` as the linear cost.
+/// memory_quadratic_divisor = 0
+/// # Optional, prices the memory by steps of `[first page, cost per page]` instead of
+/// # `memory_page` if not empty, see `rules::GrowCost::Steps`. `memory_page` may be
+/// # left out then.
+/// memory_steps = [[0, 8192], [16, 262144]]
+/// # Optional, additional cost of every target of a `br_table`.
+/// br_table_target = 0
+/// # Optional, additional cost of every iteration of a loop.
+/// loop_iteration = 0
+///
+/// [instructions]
+/// # Instruction classes, named as by `InstructionType::from_str`, with their
+/// # cost, or "regular", or "forbidden".
+/// mul = 4
+/// float = "forbidden"
+/// ```
+///
+/// The `Display` implementation writes a schedule in the same format, with a
+/// preset written out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+	pub regular: u32,
+	pub memory_page: u32,
+	pub memory_quadratic_divisor: u32,
+	pub memory_steps: Vec<(u32, u32)>,
+	pub br_table_target: u32,
+	pub loop_iteration: u32,
+	pub instructions: BTreeMap<InstructionType, Metering>,
+}
+
+impl Schedule {
+	/// Names of the presets, see `preset`.
+	pub const PRESETS: &'static [&'static str] = &["ewasm", "pwasm", "strict-integer"];
+
+	/// The schedule of a named preset, or an error if the name is unknown.
+	///
+	/// - `ewasm`: every instruction costs 1 and growing the memory costs 262144
+	///   per page. Floating point instructions are forbidden.
+	/// - `pwasm`: the costs of the Parity runtime, where loads and stores cost 2,
+	///   multiplications 4, divisions 16 and growing the memory 8192 per page.
+	///   Floating point instructions are forbidden.
+	/// - `strict-integer`: as `ewasm`, but reinterpretations and the memory size
+	///   instructions are forbidden too, restricting contracts to integer
+	///   instructions on a memory of fixed size.
+	pub fn preset(name: &str) -> Result<Self, ()> {
+		let mut schedule = Schedule {
+			regular: 1,
+			memory_page: 262144,
+			memory_quadratic_divisor: 0,
+			memory_steps: Vec::new(),
+			br_table_target: 0,
+			loop_iteration: 0,
+			instructions: BTreeMap::new(),
+		};
+		let mut forbidden = vec![
+			InstructionType::Float,
+			InstructionType::FloatComparsion,
+			InstructionType::FloatConst,
+			InstructionType::FloatConversion,
+		];
+
+		match name {
+			"ewasm" => { },
+			"pwasm" => {
+				schedule.memory_page = 8192;
+				schedule.instructions.insert(InstructionType::Load, Metering::Fixed(2));
+				schedule.instructions.insert(InstructionType::Store, Metering::Fixed(2));
+				schedule.instructions.insert(InstructionType::Mul, Metering::Fixed(4));
+				schedule.instructions.insert(InstructionType::Div, Metering::Fixed(16));
+			},
+			"strict-integer" => {
+				schedule.memory_page = 0;
+				forbidden.push(InstructionType::Reinterpretation);
+				forbidden.push(InstructionType::CurrentMemory);
+				forbidden.push(InstructionType::GrowMemory);
+			},
+			_ => return Err(()),
+		}
+
+		schedule.instructions.extend(forbidden.into_iter().map(|t| (t, Metering::Forbidden)));
+		Ok(schedule)
+	}
+
+	/// Parse a schedule.
+	pub fn parse(source: &str) -> Result<Self, Error> {
+		let mut regular = None;
+		let mut memory_page = None;
+		let mut memory_quadratic_divisor = 0;
+		let mut memory_steps = Vec::new();
+		let mut br_table_target = 0;
+		let mut loop_iteration = 0;
+		let mut instructions = BTreeMap::new();
+		let mut in_instructions = false;
+		let mut first_key = true;
+		let mut listed = BTreeSet::new();
+
+		for (line_no, line) in source.lines().enumerate() {
+			let invalid = |reason| Error::Line { line: line_no + 1, reason: reason };
+
+			let line = line.splitn(2, '#').next().unwrap_or("").trim();
+			if line.is_empty() {
+				continue;
+			}
+
+			if line.starts_with('[') {
+				if line != "[instructions]" {
+					return Err(invalid("unknown section"));
+				}
+				in_instructions = true;
+				continue;
+			}
+
+			let mut parts = line.splitn(2, '=');
+			let key = parts.next().unwrap_or("").trim();
+			let value = parts.next().ok_or_else(|| invalid("expected `key = value`"))?.trim();
+
+			if key == "preset" && !in_instructions {
+				if !first_key {
+					return Err(invalid("`preset` must come before the other keys"));
+				}
+				let name = value.trim_matches('"');
+				let preset = Schedule::preset(name).map_err(|_| invalid("unknown preset"))?;
+				regular = Some(preset.regular);
+				memory_page = Some(preset.memory_page);
+				memory_quadratic_divisor = preset.memory_quadratic_divisor;
+				memory_steps = preset.memory_steps;
+				br_table_target = preset.br_table_target;
+				loop_iteration = preset.loop_iteration;
+				instructions = preset.instructions;
+				first_key = false;
+				continue;
+			}
+			first_key = false;
+
+			if in_instructions {
+				let instruction_type = key.parse::<InstructionType>()
+					.map_err(|_| invalid("unknown instruction class"))?;
+				let metering = match value {
+					"\"forbidden\"" => Metering::Forbidden,
+					"\"regular\"" => Metering::Regular,
+					cost => Metering::Fixed(cost.parse().map_err(|_| invalid("invalid cost"))?),
+				};
+				// Classes of the preset can be overridden, but only once.
+				if !listed.insert(instruction_type) {
+					return Err(invalid("duplicate instruction class"));
+				}
+				instructions.insert(instruction_type, metering);
+			} else if key == "memory_steps" {
+				memory_steps = parse_steps(value).ok_or_else(|| invalid("invalid steps"))?;
+			} else {
+				let cost = value.parse().map_err(|_| invalid("invalid cost"))?;
+				match key {
+					"regular" => regular = Some(cost),
+					"memory_page" => memory_page = Some(cost),
+					"memory_quadratic_divisor" => memory_quadratic_divisor = cost,
+					"br_table_target" => br_table_target = cost,
+					"loop_iteration" => loop_iteration = cost,
+					_ => return Err(invalid("unknown key")),
+				}
+			}
+		}
+
+		// The cost per page is only needed without steps.
+		let memory_page = match memory_page {
+			Some(cost) => cost,
+			None if !memory_steps.is_empty() => 0,
+			None => return Err(Error::MissingKey("memory_page")),
+		};
+
+		Ok(Schedule {
+			regular: regular.ok_or(Error::MissingKey("regular"))?,
+			memory_page: memory_page,
+			memory_quadratic_divisor: memory_quadratic_divisor,
+			memory_steps: memory_steps,
+			br_table_target: br_table_target,
+			loop_iteration: loop_iteration,
+			instructions: instructions,
+		})
+	}
+
+	/// Build the metering rules with the costs of this schedule.
+	pub fn to_rules(&self) -> rules::Set {
+		let grow_cost = match self.memory_quadratic_divisor {
+			_ if !self.memory_steps.is_empty() => rules::GrowCost::Steps(self.memory_steps.clone()),
+			0 => rules::GrowCost::Linear(self.memory_page),
+			divisor => rules::GrowCost::Quadratic { linear: self.memory_page, quadratic_divisor: divisor },
+		};
+		rules::Set::new(self.regular, self.instructions.clone())
+			.with_grow_curve(grow_cost)
+			.with_br_table_target_cost(self.br_table_target)
+			.with_loop_iteration_cost(self.loop_iteration)
+	}
+}
+
+/// Parses the value of `memory_steps`, e.g. `[[0, 8192], [16, 262144]]`.
+fn parse_steps(value: &str) -> Option<Vec<(u32, u32)>> {
+	let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+	if !value.starts_with('[') || !value.ends_with(']') || value.len() < 2 {
+		return None;
+	}
+	let steps = &value[1..value.len() - 1];
+	if steps.is_empty() {
+		return Some(Vec::new());
+	}
+	if !steps.starts_with('[') || !steps.ends_with(']') || steps.len() < 2 {
+		return None;
+	}
+
+	steps[1..steps.len() - 1].split("],[").map(|step| {
+		let mut numbers = step.split(',');
+		let first_page = numbers.next()?.parse().ok()?;
+		let cost = numbers.next()?.parse().ok()?;
+		match numbers.next() {
+			Some(_) => None,
+			None => Some((first_page, cost)),
+		}
+	}).collect()
+}
+
+impl fmt::Display for Schedule {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "regular = {}", self.regular)?;
+		writeln!(f, "memory_page = {}", self.memory_page)?;
+		writeln!(f, "memory_quadratic_divisor = {}", self.memory_quadratic_divisor)?;
+		write!(f, "memory_steps = [")?;
+		for (index, &(first_page, cost)) in self.memory_steps.iter().enumerate() {
+			if index > 0 {
+				write!(f, ", ")?;
+			}
+			write!(f, "[{}, {}]", first_page, cost)?;
+		}
+		writeln!(f, "]")?;
+		writeln!(f, "br_table_target = {}", self.br_table_target)?;
+		writeln!(f, "loop_iteration = {}", self.loop_iteration)?;
+		writeln!(f)?;
+		writeln!(f, "[instructions]")?;
+		for (instruction_type, metering) in self.instructions.iter() {
+			match *metering {
+				Metering::Regular => writeln!(f, "{} = \"regular\"", instruction_type)?,
+				Metering::Forbidden => writeln!(f, "{} = \"forbidden\"", instruction_type)?,
+				Metering::Fixed(cost) => writeln!(f, "{} = {}", instruction_type, cost)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	use crate::std::string::ToString;
+	use parity_wasm::elements::{self, Instruction};
+	use super::*;
+
+	const SCHEDULE: &str = r#"
+# Costs of the test.
+regular = 2
+memory_page = 1024
+loop_iteration = 5
+
+[instructions]
+mul = 4 # multiplications are expensive
+add = "regular"
+float = "forbidden"
+"#;
+
+	#[test]
+	fn parse() {
+		let schedule = Schedule::parse(SCHEDULE).unwrap();
+
+		assert_eq!(schedule.regular, 2);
+		assert_eq!(schedule.memory_page, 1024);
+		assert_eq!(schedule.br_table_target, 0);
+		assert_eq!(schedule.loop_iteration, 5);
+		assert_eq!(
+			schedule.instructions,
+			vec![
+				(InstructionType::Add, Metering::Regular),
+				(InstructionType::Mul, Metering::Fixed(4)),
+				(InstructionType::Float, Metering::Forbidden),
+			].into_iter().collect()
+		);
+
+		let rules = schedule.to_rules();
+		assert_eq!(rules.process(&Instruction::I32Mul), Ok(4));
+		assert_eq!(rules.process(&Instruction::I32Add), Ok(2));
+		assert_eq!(rules.process(&Instruction::F32Add), Err(()));
+		assert_eq!(rules.grow_cost(), 1024);
+		assert_eq!(rules.loop_iteration_cost(), 5);
+		assert_eq!(rules.grow_curve(), None);
+	}
+
+	#[test]
+	fn quadratic_memory() {
+		let schedule = Schedule::parse("regular = 1\nmemory_page = 3\nmemory_quadratic_divisor = 512\n").unwrap();
+
+		assert_eq!(schedule.memory_quadratic_divisor, 512);
+		assert_eq!(
+			schedule.to_rules().grow_curve(),
+			Some(&rules::GrowCost::Quadratic { linear: 3, quadratic_divisor: 512 })
+		);
+		assert_eq!(Schedule::parse(&schedule.to_string()), Ok(schedule));
+	}
+
+	#[test]
+	fn memory_steps() {
+		let schedule = Schedule::parse("regular = 1\nmemory_steps = [[0, 10], [16, 100]] # cheap at first\n").unwrap();
+
+		assert_eq!(schedule.memory_steps, vec![(0, 10), (16, 100)]);
+		assert_eq!(schedule.memory_page, 0);
+		assert_eq!(
+			schedule.to_rules().grow_curve(),
+			Some(&rules::GrowCost::Steps(vec![(0, 10), (16, 100)]))
+		);
+		assert_eq!(Schedule::parse(&schedule.to_string()), Ok(schedule));
+
+		let schedule = Schedule::parse("regular = 1\nmemory_page = 3\nmemory_steps = []\n").unwrap();
+		assert_eq!(schedule.to_rules().grow_curve(), None);
+		assert_eq!(schedule.to_rules().grow_cost(), 3);
+
+		for steps in &["[[0, 10]", "[0, 10]", "[[0, 10], [16]]", "[[0, 10, 2]]", "[[0, -1]]", "[", "10"] {
+			assert_eq!(
+				Schedule::parse(&format!("regular = 1\nmemory_steps = {}\n", steps)),
+				Err(Error::Line { line: 2, reason: "invalid steps" })
+			);
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let schedule = Schedule::parse(SCHEDULE).unwrap();
+
+		assert_eq!(Schedule::parse(&schedule.to_string()), Ok(schedule));
+	}
+
+	#[test]
+	fn presets() {
+		for name in Schedule::PRESETS {
+			let schedule = Schedule::preset(name).unwrap();
+			assert_eq!(schedule.instructions.get(&InstructionType::Float), Some(&Metering::Forbidden));
+		}
+		assert_eq!(Schedule::preset("evm"), Err(()));
+
+		let rules = rules::Set::with_preset("strict-integer").unwrap();
+		assert_eq!(rules.process(&Instruction::GrowMemory(0)), Err(()));
+		assert_eq!(rules.process(&Instruction::I32Add), Ok(1));
+		assert_eq!(rules.gas_import(), &rules::GasImport::default());
+
+		let rules = rules::Set::with_preset("pwasm").unwrap();
+		assert_eq!(rules.process(&Instruction::I32Mul), Ok(4));
+		assert_eq!(
+			rules.gas_import(),
+			&rules::GasImport {
+				module: "env".into(),
+				field: "gas".into(),
+				param: elements::ValueType::I32,
+			}
+		);
+	}
+
+	#[test]
+	fn parse_preset() {
+		let schedule = Schedule::parse("preset = \"pwasm\"\nregular = 2\n[instructions]\ndiv = 20\n").unwrap();
+
+		let mut expected = Schedule::preset("pwasm").unwrap();
+		expected.regular = 2;
+		expected.instructions.insert(InstructionType::Div, Metering::Fixed(20));
+		assert_eq!(schedule, expected);
+
+		assert_eq!(
+			Schedule::parse("regular = 2\npreset = \"pwasm\"\n"),
+			Err(Error::Line { line: 2, reason: "`preset` must come before the other keys" })
+		);
+		assert_eq!(
+			Schedule::parse("preset = \"evm\"\n"),
+			Err(Error::Line { line: 1, reason: "unknown preset" })
+		);
+	}
+
+	#[test]
+	fn errors() {
+		assert_eq!(
+			Schedule::parse("regular = 1\nmemory_page = 1\n[instructions]\nmull = 1\n"),
+			Err(Error::Line { line: 4, reason: "unknown instruction class" })
+		);
+		assert_eq!(
+			Schedule::parse("regular = 1\nmemory_page = lots\n"),
+			Err(Error::Line { line: 2, reason: "invalid cost" })
+		);
+		assert_eq!(Schedule::parse("regular = 1\n"), Err(Error::MissingKey("memory_page")));
+	}
+}
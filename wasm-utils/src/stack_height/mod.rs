@@ -48,8 +48,8 @@
 //!   between the frames.
 //! - upon entry into the function entire stack frame is allocated.
 
-use std::string::String;
-use std::vec::Vec;
+use crate::std::string::String;
+use crate::std::vec::Vec;
 
 use parity_wasm::elements::{self, Type};
 use parity_wasm::builder;
@@ -154,6 +154,26 @@ pub fn inject_limiter(
 	Ok(module)
 }
 
+/// Returns true if `body` is a thunk generated by `inject_limiter`.
+pub(crate) fn is_thunk(body: &elements::FuncBody) -> bool {
+	use parity_wasm::elements::Instruction::*;
+
+	let code = body.code().elements();
+	// Thunks push their arguments and make an instrumented call.
+	let args = code.iter().enumerate()
+		.take_while(|&(arg_idx, instruction)| *instruction == GetLocal(arg_idx as u32))
+		.count();
+	let call = &code[args..];
+	if call.len() != 16 || call[15] != End {
+		return false;
+	}
+	match (&call[0], &call[1], &call[5], &call[10]) {
+		(&GetGlobal(stack_height), &I32Const(stack_cost), &I32Const(stack_limit), &Call(callee_idx)) =>
+			call[..15] == instrument_call!(callee_idx, stack_cost, stack_height, stack_limit as u32)[..],
+		_ => false,
+	}
+}
+
 /// Generate a new global that will be used for tracking current stack height.
 fn generate_stack_height_global(ctx: &mut Context, module: &mut elements::Module) {
 	let global_entry = builder::global()
@@ -1,8 +1,5 @@
-#[cfg(features = "std")]
-use std::collections::{HashSet as Set};
-#[cfg(not(features = "std"))]
-use std::collections::{BTreeSet as Set};
-use std::vec::Vec;
+use crate::std::collections::{BTreeSet as Set};
+use crate::std::vec::Vec;
 
 use parity_wasm::elements;
 
@@ -91,3 +91,20 @@ def_stack_height_test!(simple);
 def_stack_height_test!(table);
 def_stack_height_test!(global);
 def_stack_height_test!(imports);
+
+macro_rules! def_gas_test {
+	( $name:ident, $rules:expr ) => {
+		#[test]
+		fn $name() {
+			run_diff_test("gas", concat!(stringify!($name), ".wat"), |input| {
+				let module = elements::deserialize_buffer(input).expect("Failed to deserialize");
+				let instrumented = utils::inject_gas_counter(module, &$rules).expect("Failed to instrument with gas metering");
+				elements::serialize(instrumented).expect("Failed to serialize")
+			});
+		}
+	};
+}
+
+def_gas_test!(precise_branch, utils::rules::Set::default().with_precise_metering());
+def_gas_test!(precise_loop, utils::rules::Set::default().with_precise_metering());
+def_gas_test!(precise_ifelse, utils::rules::Set::default().with_precise_metering());
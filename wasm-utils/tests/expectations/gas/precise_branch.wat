@@ -0,0 +1,21 @@
+(module
+  (type (;0;) (func (param i32) (result i32)))
+  (type (;1;) (func (param i64)))
+  (import "ethereum" "useGas" (func (;0;) (type 1)))
+  (func (;1;) (type 0) (param i32) (result i32)
+    i64.const 1
+    call 0
+    block  ;; label = @1
+      i64.const 2
+      call 0
+      get_local 0
+      br_if 0 (;@1;)
+      i64.const 3
+      call 0
+      i32.const 1
+      drop
+    end
+    i64.const 2
+    call 0
+    get_local 0)
+  (export "call" (func 1)))
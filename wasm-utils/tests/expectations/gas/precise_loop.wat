@@ -0,0 +1,21 @@
+(module
+  (type (;0;) (func (param i32)))
+  (type (;1;) (func (param i64)))
+  (import "ethereum" "useGas" (func (;0;) (type 1)))
+  (func (;1;) (type 0) (param i32)
+    i64.const 1
+    call 0
+    loop  ;; label = @1
+      i64.const 5
+      call 0
+      get_local 0
+      i32.const 1
+      i32.sub
+      tee_local 0
+      br_if 0 (;@1;)
+      i64.const 1
+      call 0
+    end
+    i64.const 1
+    call 0)
+  (export "call" (func 1)))
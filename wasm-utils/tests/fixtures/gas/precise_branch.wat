@@ -0,0 +1,11 @@
+(module
+	(func (export "call") (param i32) (result i32)
+		block
+			get_local 0
+			br_if 0
+			i32.const 1
+			drop
+		end
+		get_local 0
+	)
+)
@@ -0,0 +1,11 @@
+(module
+	(func (export "call") (param i32) (result i32)
+		get_local 0
+		if (result i32)
+			i32.const 1
+		else
+			i32.const 2
+			return
+		end
+	)
+)
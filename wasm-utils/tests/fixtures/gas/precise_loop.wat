@@ -0,0 +1,11 @@
+(module
+	(func (export "call") (param i32)
+		loop
+			get_local 0
+			i32.const 1
+			i32.sub
+			tee_local 0
+			br_if 0
+		end
+	)
+)